@@ -23,11 +23,195 @@ in
             type = types.str;
             description = "User group that proxy runs";
           };
+          offline = mkOption {
+            type = types.bool;
+            default = false;
+            description = "Serve cached responses regardless of freshness, toggled at runtime by SIGUSR1";
+          };
+          maxSize = mkOption {
+            type = types.nullOr types.str;
+            default = null;
+            example = "10G";
+            description = "Maximum total size of cached content of each server";
+          };
+          maxAge = mkOption {
+            type = types.nullOr types.str;
+            default = null;
+            example = "30d";
+            description = "Remove cached entries not accessed for this long";
+          };
+          eviction = mkOption {
+            type = types.enum [
+              "lru"
+              "lfu"
+            ];
+            default = "lru";
+            description = "Entries evicted first when the cache exceeds maximum size";
+          };
+          headUpstream = mkOption {
+            type = types.bool;
+            default = false;
+            description = "Forward HEAD requests of uncached paths to upstream instead of filling the cache";
+          };
+          upstreamHeaders = {
+            set = mkOption {
+              type = types.attrsOf types.str;
+              default = { };
+              example = {
+                "User-Agent" = "local_cdn";
+              };
+              description = "Headers set on requests to upstream, User-Agent is curl unless configured";
+            };
+            remove = mkOption {
+              type = types.listOf types.str;
+              default = [ ];
+              description = "Client headers never sent to upstream";
+            };
+            pass = mkOption {
+              type = types.listOf types.str;
+              default = [ ];
+              description = "Client headers sent to upstream even in privacy mode";
+            };
+            privacy = mkOption {
+              type = types.bool;
+              default = false;
+              description = "Strip identifying client headers like Cookie, Referer and Accept-Language";
+            };
+          };
+          egressProxy = {
+            url = mkOption {
+              type = types.nullOr types.str;
+              default = null;
+              example = "socks5://proxy.example.com:1080";
+              description = "Connect to upstream through HTTP CONNECT (http://) or SOCKS5 (socks5://) proxy";
+            };
+            authFile = mkOption {
+              type = types.nullOr types.str;
+              default = null;
+              description = "File outside the nix store containing `user:password` of egress proxy";
+            };
+          };
+          upstreamTls = {
+            caFiles = mkOption {
+              type = types.listOf types.path;
+              default = [ ];
+              description = "PEM bundles of additional root certificates trusted for upstream";
+            };
+            nativeRoots = mkOption {
+              type = types.bool;
+              default = true;
+              description = "Trust root certificates of the system, caFiles must be given if disabled";
+            };
+            spkiPins = mkOption {
+              type = types.attrsOf (types.listOf types.str);
+              default = { };
+              example = {
+                "cdn.example.com" = [ "sha256//..." ];
+              };
+              description = "Accepted public key hashes of upstream certificates, by TLS server name";
+            };
+            clientCert = mkOption {
+              type = types.nullOr types.path;
+              default = null;
+              description = "PEM certificate chain presented to upstream requesting client certificates";
+            };
+            clientKeyFile = mkOption {
+              type = types.nullOr types.str;
+              default = null;
+              description = "File outside the nix store containing private key of clientCert";
+            };
+          };
+          admin = mkOption {
+            type = types.bool;
+            default = false;
+            description = "Serve admin API on admin.sock in runtime directory, accessible only by proxy user";
+          };
+          metricsAddress = mkOption {
+            type = types.nullOr types.str;
+            default = null;
+            example = "127.0.0.1:9100";
+            description = "TCP address serving Prometheus metrics on /metrics";
+          };
+          pins = mkOption {
+            type = types.attrsOf types.str;
+            default = { };
+            example = {
+              "https://code.jquery.com/jquery-3.7.1.min.js" = "sha256-...";
+            };
+            description = "Subresource integrity of URLs, e.g. `url` and `hash` of library packages, bodies not matching are never cached or served";
+          };
+          rules = mkOption {
+            type = types.listOf (types.attrsOf types.anything);
+            default = [ ];
+            example = [
+              {
+                path = "/ajax/libs/**";
+                immutable = true;
+              }
+              {
+                path = "/api/*";
+                bypass = true;
+              }
+            ];
+            description = "Rules overriding cache policy of paths, the first matching rule is used";
+          };
+          cacheKey = {
+            sortQuery = mkOption {
+              type = types.bool;
+              default = false;
+              description = "Sort query parameters in cache keys";
+            };
+            dropParams = mkOption {
+              type = types.listOf types.str;
+              default = [ ];
+              example = [ "utm_*" ];
+              description = "Drop query parameters with name matching the globs from cache keys";
+            };
+            collapseSlashes = mkOption {
+              type = types.bool;
+              default = false;
+              description = "Collapse consecutive slashes of path in cache keys";
+            };
+            decodeUnreserved = mkOption {
+              type = types.bool;
+              default = false;
+              description = "Decode percent-encoded unreserved characters in cache keys";
+            };
+            lowercasePath = mkOption {
+              type = types.bool;
+              default = false;
+              description = "Lowercase path in cache keys";
+            };
+          };
           servers = mkOption {
             type = types.attrsOf (
               types.submodule {
                 options = {
                   cert = cert.mkOption { default_ca = "proxy"; };
+                  serverName = mkOption {
+                    type = types.nullOr types.str;
+                    default = null;
+                    description = "TLS server name of upstream, the domain by default";
+                  };
+                  mirrors = mkOption {
+                    type = types.listOf (
+                      types.submodule {
+                        options = {
+                          host = mkOption {
+                            type = types.str;
+                            description = "Host of the mirror, with port if it is not the default";
+                          };
+                          serverName = mkOption {
+                            type = types.nullOr types.str;
+                            default = null;
+                            description = "TLS server name of the mirror, the host by default";
+                          };
+                        };
+                      }
+                    );
+                    default = [ ];
+                    description = "Mirrors of upstream tried in order when it fails, sharing its cache";
+                  };
                 };
               }
             );
@@ -42,7 +226,12 @@ in
         in
         lib.mkIf cfg.enable (
           let
+            origin = host: serverName: if serverName == null then host else "${host}=${serverName}";
             servers = builtins.mapAttrs (domain: config: {
+              arg = lib.concatStringsSep "," (
+                [ (origin domain config.serverName) ]
+                ++ builtins.map (m: origin m.host m.serverName) config.mirrors
+              );
               cert_config = cert.mkConfig {
                 name = domain;
                 distinguished_name = {
@@ -54,22 +243,64 @@ in
             }) cfg.servers;
           in
           {
-            systemd.services."local_cdn-proxy@" =
+            systemd.services."local_cdn-proxy" =
               let
                 bin_drv = pkgs.callPackage package { };
               in
               {
-                description = "local cdn caching proxy for %I";
+                description = "local cdn caching proxy";
+                wantedBy = [ "multi-user.target" ];
                 serviceConfig = {
                   ExecStart = ''
                     ${bin_drv}/bin/local_cdn-proxy \
                       --log-output journal \
+                      ${lib.optionalString cfg.offline "--offline"} \
+                      ${lib.optionalString cfg.headUpstream "--head-upstream"} \
+                      ${lib.optionalString (cfg.maxSize != null) "--max-size ${cfg.maxSize}"} \
+                      ${lib.optionalString (cfg.maxAge != null) "--max-age ${cfg.maxAge}"} \
+                      --eviction ${cfg.eviction} \
+                      ${lib.optionalString cfg.admin "--admin-unix \"''${RUNTIME_DIRECTORY}/admin.sock\""} \
+                      ${lib.optionalString (cfg.metricsAddress != null) "--metrics-tcp ${cfg.metricsAddress}"} \
+                      ${lib.optionalString (cfg.pins != { }) "--pins ${pkgs.writeText "pins.json" (builtins.toJSON cfg.pins)}"} \
+                      ${lib.optionalString (cfg.rules != [ ]) "--rules ${pkgs.writeText "rules.json" (builtins.toJSON cfg.rules)}"} \
+                      ${lib.optionalString cfg.cacheKey.sortQuery "--sort-query"} \
+                      ${lib.escapeShellArgs (lib.concatMap (p: [ "--drop-param" p ]) cfg.cacheKey.dropParams)} \
+                      ${lib.optionalString cfg.cacheKey.collapseSlashes "--collapse-slashes"} \
+                      ${lib.optionalString cfg.cacheKey.decodeUnreserved "--decode-unreserved"} \
+                      ${lib.optionalString cfg.cacheKey.lowercasePath "--lowercase-path"} \
+                      ${lib.optionalString (
+                        cfg.upstreamHeaders != {
+                          set = { };
+                          remove = [ ];
+                          pass = [ ];
+                          privacy = false;
+                        }
+                      ) "--upstream-headers ${pkgs.writeText "upstream-headers.json" (builtins.toJSON cfg.upstreamHeaders)}"} \
+                      ${lib.optionalString (cfg.egressProxy.url != null) "--egress-proxy ${lib.escapeShellArg cfg.egressProxy.url}"} \
+                      ${lib.optionalString (cfg.egressProxy.authFile != null) "--egress-proxy-auth \"''${CREDENTIALS_DIRECTORY}/egress-proxy-auth\""} \
+                      ${lib.escapeShellArgs (lib.concatMap (f: [ "--ca-file" f ]) cfg.upstreamTls.caFiles)} \
+                      ${lib.optionalString (!cfg.upstreamTls.nativeRoots) "--no-native-roots"} \
+                      ${lib.escapeShellArgs (
+                        lib.concatLists (
+                          lib.mapAttrsToList (
+                            name: pins: lib.concatMap (pin: [ "--spki-pin" "${name}=${pin}" ]) pins
+                          ) cfg.upstreamTls.spkiPins
+                        )
+                      )} \
+                      ${lib.optionalString (cfg.upstreamTls.clientCert != null) "--client-cert ${cfg.upstreamTls.clientCert} --client-key \"''${CREDENTIALS_DIRECTORY}/client-key\""} \
                       --unix "''${RUNTIME_DIRECTORY}/proxy.sock" \
                       ''${CACHE_DIRECTORY} \
-                      %i
+                      ${lib.escapeShellArgs (builtins.map (server: server.arg) (builtins.attrValues servers))}
                   '';
-                  RuntimeDirectory = [ "local_cdn/proxy/%i" ];
-                  CacheDirectory = [ "local_cdn/proxy/%i" ];
+                  RuntimeDirectory = [ "local_cdn/proxy" ];
+                  CacheDirectory = [ "local_cdn/proxy" ];
+                  LoadCredential =
+                    lib.optional (
+                      cfg.egressProxy.authFile != null
+                    ) "egress-proxy-auth:${cfg.egressProxy.authFile}"
+                    ++ lib.optional (
+                      cfg.upstreamTls.clientKeyFile != null
+                    ) "client-key:${cfg.upstreamTls.clientKeyFile}";
 
                   ProtectProc = "noaccess";
                   ProcSubset = "pid";
@@ -117,7 +348,7 @@ in
               sslCertificate = cfg.cert_config.certificate;
               sslCertificateKey = cfg.cert_config.key;
               locations."/" = {
-                proxyPass = "http://unix:/run/local_cdn/proxy/${domain}/proxy.sock:";
+                proxyPass = "http://unix:/run/local_cdn/proxy/proxy.sock:";
                 extraConfig = "proxy_set_header Host $host;";
               };
             }) servers;
@@ -0,0 +1,462 @@
+use std::{
+    convert::Infallible, fmt::Display, future::Future, path::Path, sync::Arc, time::SystemTime,
+};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use http::{header, uri::Authority, Method, Request, Response, StatusCode};
+use http_body_util::Full;
+use http_cache_semantics::CachePolicy;
+use hyper::body::{Bytes, Incoming};
+use sha2::{Digest, Sha256};
+use tower_service::Service;
+
+use crate::{
+    entry::{self, Loaded},
+    flight::Flight,
+    gc,
+    host::HostRouter,
+    CacheProxy, FillOutcome, Filled, ForceRevalidation, ProxyError, UpstreamBody,
+};
+
+type AdminResponse = Response<Full<Bytes>>;
+
+fn json(status: StatusCode, value: &impl serde::Serialize) -> AdminResponse {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(serde_json::to_vec(value).unwrap())))
+        .unwrap()
+}
+
+fn error(status: StatusCode, msg: impl Display) -> AdminResponse {
+    json(status, &serde_json::json!({ "error": msg.to_string() }))
+}
+
+/// Decode percent-encoded query component.
+fn percent_decode(s: &str) -> String {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hex = [bytes.next(), bytes.next()];
+                match hex.map(|h| h.and_then(|h| (h as char).to_digit(16))) {
+                    [Some(h), Some(l)] => out.push((h * 16 + l) as u8),
+                    _ => {
+                        out.push(b'%');
+                        out.extend(hex.into_iter().flatten());
+                    }
+                }
+            }
+            b => out.push(b),
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn query_param(req: &Request<Incoming>, name: &str) -> Option<String> {
+    req.uri()
+        .query()?
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| percent_decode(v))
+}
+
+#[derive(serde::Serialize)]
+struct EntryInfo {
+    key: String,
+    /// Request uri of the entry, if it differs from the normalized key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    /// Upstream mirror the response was received from, if recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mirror: Option<String>,
+    /// `entry` for cached response, `vary` for index of variants, or
+    /// `encoded` for compressed body.
+    kind: &'static str,
+    size: Option<u64>,
+    stored: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vary: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy: Option<PolicyInfo>,
+}
+
+#[derive(serde::Serialize)]
+struct PolicyInfo {
+    storable: bool,
+    stale: bool,
+    /// Age in seconds.
+    age: u64,
+    /// Remaining freshness lifetime in seconds.
+    ttl: u64,
+}
+impl PolicyInfo {
+    fn new(policy: &CachePolicy, now: SystemTime) -> Self {
+        Self {
+            storable: policy.is_storable(),
+            stale: policy.is_stale(now),
+            age: policy.age(now).as_secs(),
+            ttl: policy.time_to_live(now).as_secs(),
+        }
+    }
+}
+
+/// Result of revalidating one entry.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Revalidation {
+    Updated,
+    NotModified,
+    /// Entry is fresh for the revalidation request, upstream is not requested.
+    Skipped,
+    /// Upstream can't be reached or its body failed, stale entry is kept.
+    Failed,
+    /// Key holds an index of variants, which are revalidated by their keys.
+    VaryIndex,
+    /// Entry is already being updated by another request.
+    InProgress,
+}
+
+/// Admin API of caches of a [`HostRouter`].
+///
+/// Each request selects the cache of a host with `host` query parameter,
+/// which can be omitted if there is only one host.
+///
+/// - `GET /entries?prefix=`: list entries with key starting with `prefix`
+/// - `GET /entry?key=`: show stored policy and headers of an entry
+/// - `DELETE /entry?key=`: purge an entry and its variants
+/// - `DELETE /entries?prefix=`: purge entries with key starting with `prefix`,
+///   which must be given, `prefix=` purges all entries
+/// - `POST /entry/revalidate?key=`: revalidate an entry and its variants
+/// - `POST /entries/revalidate?prefix=`: revalidate entries with key starting with `prefix`
+///
+/// If a token is set, requests without it as bearer token are refused.
+#[derive(Clone)]
+pub struct Admin<S> {
+    hosts: HostRouter<S>,
+    /// Digest of the token, so that comparing it takes the same time.
+    token: Option<[u8; 32]>,
+}
+impl<S: Clone> Admin<S> {
+    pub fn new(hosts: HostRouter<S>) -> Self {
+        Self { hosts, token: None }
+    }
+    /// Require requests to send `token` in `Authorization: Bearer` header.
+    pub fn token(mut self, token: Option<&str>) -> Self {
+        self.token = token.map(|t| Sha256::digest(t).into());
+        self
+    }
+    fn is_authorized(&self, req: &Request<Incoming>) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|t| <[u8; 32]>::from(Sha256::digest(t.trim())) == *token)
+    }
+    fn select(&self, req: &Request<Incoming>) -> Result<HostAdmin<S>, (StatusCode, String)> {
+        let proxy = match query_param(req, "host") {
+            Some(host) => {
+                let authority = Authority::try_from(host.as_str())
+                    .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+                self.hosts
+                    .get(&authority)
+                    .ok_or_else(|| (StatusCode::NOT_FOUND, "unknown host".to_string()))?
+            }
+            None => {
+                let mut hosts = self.hosts.hosts();
+                match (hosts.next(), hosts.next()) {
+                    (Some(host), None) => self.hosts.get(host).unwrap(),
+                    _ => return Err((StatusCode::BAD_REQUEST, "missing host".to_string())),
+                }
+            }
+        };
+        Ok(HostAdmin {
+            proxy: proxy.clone(),
+        })
+    }
+}
+
+/// Admin API of the cache of one host.
+struct HostAdmin<S> {
+    proxy: CacheProxy<S>,
+}
+impl<S> HostAdmin<S> {
+    /// Run cache I/O `f` with the cache root in the blocking thread pool.
+    ///
+    /// The returned future doesn't borrow `self`, which may not be `Sync`.
+    fn blocking<T, F>(&self, f: F) -> impl Future<Output = T>
+    where
+        F: FnOnce(&Path) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let root = Arc::clone(&self.proxy.root);
+        crate::blocking(move || f(&root))
+    }
+    fn list(
+        &self,
+        prefix: &str,
+    ) -> impl Future<Output = Result<Vec<cacache::Metadata>, cacache::Error>> {
+        let prefix = prefix.to_string();
+        self.blocking(move |root| list(root, &prefix))
+    }
+    /// Keys of entry `key` and its variants.
+    fn with_variants(
+        &self,
+        key: &str,
+    ) -> impl Future<Output = Result<Vec<cacache::Metadata>, cacache::Error>> {
+        let key = key.to_string();
+        self.blocking(move |root| {
+            let variant_prefix = format!("{key}\n");
+            Ok(list(root, &key)?
+                .into_iter()
+                .filter(|md| md.key == key || md.key.starts_with(&variant_prefix))
+                .collect())
+        })
+    }
+    fn show_list(&self, entries: Vec<cacache::Metadata>) -> impl Future<Output = AdminResponse> {
+        self.blocking(move |root| {
+            let now = SystemTime::now();
+            json(
+                StatusCode::OK,
+                &entries
+                    .iter()
+                    .map(|md| info(root, md, now))
+                    .collect::<Vec<_>>(),
+            )
+        })
+    }
+    fn show(&self, key: &str) -> impl Future<Output = AdminResponse> {
+        let key = key.to_string();
+        self.blocking(move |root| {
+            let md = match cacache::index::find(root, &key) {
+                Ok(Some(md)) => md,
+                Ok(None) => return error(StatusCode::NOT_FOUND, "entry not found"),
+                Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e),
+            };
+            let info = info(root, &md, SystemTime::now());
+            if info.kind != "entry" {
+                return json(StatusCode::OK, &info);
+            }
+            match entry::decode(root, &md) {
+                Ok(stored) => json(
+                    StatusCode::OK,
+                    &serde_json::json!({ "entry": info, "stored_policy": stored.policy() }),
+                ),
+                Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+            }
+        })
+    }
+    fn purge(&self, entries: Vec<cacache::Metadata>) -> impl Future<Output = AdminResponse> {
+        self.blocking(move |root| {
+            let mut purged = Vec::new();
+            for md in entries {
+                if let Err(e) = cacache::remove_sync(root, &md.key) {
+                    return error(StatusCode::INTERNAL_SERVER_ERROR, e);
+                }
+                tracing::info!(key = md.key, "entry purged");
+                purged.push(md.key);
+            }
+            json(StatusCode::OK, &serde_json::json!({ "purged": purged }))
+        })
+    }
+}
+
+/// Entries of cache `root` with key starting with `prefix`, sorted by key.
+fn list(root: &Path, prefix: &str) -> Result<Vec<cacache::Metadata>, cacache::Error> {
+    let mut entries = gc::list_index(root)?;
+    entries.retain(|md| md.key.starts_with(prefix));
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(entries)
+}
+
+/// Summary of entry `md` of cache `root`.
+fn info(root: &Path, md: &cacache::Metadata, now: SystemTime) -> EntryInfo {
+    let metadata = entry::EntryMetadata::read(md);
+    let mut info = EntryInfo {
+        key: md.key.clone(),
+        uri: metadata
+            .uri
+            .filter(|u| *u != entry::split_variant_key(&md.key).0),
+        mirror: metadata.mirror,
+        policy: None,
+        kind: "entry",
+        size: gc::content_size(root, &md.integrity),
+        stored: httpdate::fmt_http_date(
+            SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(md.time as u64),
+        ),
+        vary: None,
+    };
+    if md.key.starts_with("encoded:") {
+        info.kind = "encoded";
+    } else if let Some(vary) = entry::read_vary_index(md) {
+        info.kind = "vary";
+        info.vary = Some(vary.iter().map(|h| h.to_string()).collect());
+    } else {
+        info.policy = metadata.policy.map(|p| PolicyInfo::new(&p, now));
+    }
+    info
+}
+
+impl<S, E> HostAdmin<S>
+where
+    S: Clone + Send + 'static,
+    S: Service<Request<UpstreamBody>, Response = Response<Incoming>, Error = E>,
+    S::Future: Send,
+    E: Display + Send + 'static,
+{
+    async fn revalidate(
+        &mut self,
+        entries: Vec<cacache::Metadata>,
+    ) -> Result<AdminResponse, ProxyError<E>> {
+        let mut results = serde_json::Map::new();
+        for md in entries {
+            if md.key.starts_with("encoded:") || entry::read_vary_index(&md).is_some() {
+                continue;
+            }
+            let r = match self.proxy.revalidate(&md.key).await {
+                Ok(r) => serde_json::to_value(r).unwrap(),
+                Err(e) => serde_json::json!({ "error": e.to_string() }),
+            };
+            results.insert(md.key, r);
+        }
+        Ok(json(StatusCode::OK, &results))
+    }
+    async fn handle(&mut self, req: Request<Incoming>) -> AdminResponse {
+        let listed = match (req.method(), req.uri().path()) {
+            (_, "/entry" | "/entry/revalidate") => match query_param(&req, "key") {
+                Some(key) => self.with_variants(&key).await.map(|e| (key, e)),
+                None => return error(StatusCode::BAD_REQUEST, "missing key"),
+            },
+            (&Method::DELETE, "/entries") => match query_param(&req, "prefix") {
+                Some(prefix) => self.list(&prefix).await.map(|e| (prefix, e)),
+                None => return error(StatusCode::BAD_REQUEST, "missing prefix"),
+            },
+            (_, "/entries" | "/entries/revalidate") => {
+                let prefix = query_param(&req, "prefix").unwrap_or_default();
+                self.list(&prefix).await.map(|e| (prefix, e))
+            }
+            _ => return error(StatusCode::NOT_FOUND, "not found"),
+        };
+        let (key, entries) = match listed {
+            Ok(v) => v,
+            Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        };
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/entries") => self.show_list(entries).await,
+            (&Method::GET, "/entry") => self.show(&key).await,
+            (&Method::DELETE, "/entry" | "/entries") => self.purge(entries).await,
+            (&Method::POST, "/entry/revalidate" | "/entries/revalidate") => self
+                .revalidate(entries)
+                .await
+                .unwrap_or_else(|e| error(StatusCode::BAD_GATEWAY, e)),
+            _ => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        }
+    }
+}
+
+impl<S, E> Service<Request<Incoming>> for Admin<S>
+where
+    S: Clone + Send + 'static,
+    S: Service<Request<UpstreamBody>, Response = Response<Incoming>, Error = E>,
+    S::Future: Send,
+    E: Display + Send + 'static,
+{
+    type Response = AdminResponse;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<AdminResponse, Infallible>>;
+    fn poll_ready(
+        &mut self,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        tracing::info!(method = %req.method(), uri = %req.uri(), "admin request");
+        if !self.is_authorized(&req) {
+            tracing::warn!("admin request without valid token");
+            let mut resp = error(StatusCode::UNAUTHORIZED, "invalid token");
+            resp.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static("Bearer"),
+            );
+            return futures_util::future::ready(Ok(resp)).boxed();
+        }
+        let mut admin = match self.select(&req) {
+            Ok(a) => a,
+            Err((status, msg)) => {
+                return futures_util::future::ready(Ok(error(status, msg))).boxed()
+            }
+        };
+        async move { Ok(admin.handle(req).await) }.boxed()
+    }
+}
+
+impl<S, E> CacheProxy<S>
+where
+    S: Clone + Send + 'static,
+    S: Service<Request<UpstreamBody>, Response = Response<Incoming>, Error = E>,
+    S::Future: Send,
+    E: Display + Send + 'static,
+{
+    /// Revalidate entry of `key` with upstream regardless of its freshness.
+    async fn revalidate(&mut self, key: &str) -> Result<Revalidation, ProxyError<E>> {
+        let not_found = || {
+            ProxyError::ReadCache(cacache::Error::EntryNotFound(
+                self.root.to_path_buf(),
+                key.to_string(),
+            ))
+        };
+        let (root, owned_key) = (Arc::clone(&self.root), key.to_string());
+        let md = crate::blocking(move || cacache::index::find(&root, &owned_key))
+            .await
+            .map_err(ProxyError::ReadCache)?
+            .ok_or_else(not_found)?;
+        // index of variants is not an entry, which must not be loaded
+        if entry::read_vary_index(&md).is_some() {
+            return Ok(Revalidation::VaryIndex);
+        }
+        let stored_uri = entry::EntryMetadata::read(&md).uri;
+        let root = Arc::clone(&self.root);
+        let entry = match crate::blocking(move || entry::load(&root, &md))
+            .await
+            .map_err(ProxyError::ReadCache)?
+        {
+            Loaded::Current(entry) | Loaded::Upgraded(entry) => entry,
+            Loaded::Evicted | Loaded::VaryIndex => return Err(not_found()),
+        };
+        let (primary, headers) = entry::split_variant_key(key);
+        // request the original uri if key is normalized
+        let uri = stored_uri.unwrap_or_else(|| primary.to_string());
+        let mut req = Request::get(uri.as_str())
+            .body(())
+            .map_err(|e| ProxyError::InvalidPath(uri.clone(), e))?
+            .into_parts()
+            .0;
+        req.headers = headers;
+        self.normalize_uri(&mut req);
+        let mut upstream_req = self.upstream_request(&req)?;
+        upstream_req.extensions.insert(ForceRevalidation);
+        let flight = match self.in_flight.join(key) {
+            Flight::Leader(g) => g,
+            Flight::Follower(_) => return Ok(Revalidation::InProgress),
+        };
+        Ok(
+            match self.update_entry(key, entry, upstream_req, flight).await? {
+                Filled::Stored(_, FillOutcome::Updated) => Revalidation::Updated,
+                Filled::Stored(_, FillOutcome::NotModified) => Revalidation::NotModified,
+                Filled::Stored(_, FillOutcome::Skipped) => Revalidation::Skipped,
+                Filled::Streaming(_, body) => match body.drain().await {
+                    true => Revalidation::Updated,
+                    false => Revalidation::Failed,
+                },
+                Filled::Stale(_, _) => Revalidation::Failed,
+            },
+        )
+    }
+}
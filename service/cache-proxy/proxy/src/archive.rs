@@ -0,0 +1,643 @@
+//! Portable archive of cached responses.
+//!
+//! An archive is a sequence of CBOR values: a [`Header`] naming the
+//! authority of the cache, then [`Record`]s, terminated by [`Record::End`]
+//! with the number of records, so that a truncated archive is detected.
+
+use std::{
+    fmt::Display,
+    io::{self, Read, Seek, Write},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use http::{uri::Authority, HeaderName};
+use http_cache_semantics::CachePolicy;
+use ssri::Integrity;
+
+use crate::{
+    entry::{self, Stored},
+    gc,
+};
+
+const FORMAT: &str = "local_cdn-proxy archive";
+/// Version of archives written, version 1 has no digest of bodies.
+const VERSION: u32 = 2;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+    authority: String,
+}
+
+/// Cached response in [`Record::Entry`].
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ArchivedEntry {
+    policy: CachePolicy,
+    body: Bytes,
+    /// Digest of `body`, missing in archives of version 1.
+    #[serde(default)]
+    digest: Option<Integrity>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Record {
+    /// Cached response, `entry` is the encoded [`ArchivedEntry`] with digest `integrity`.
+    Entry {
+        key: String,
+        /// Request uri if recorded, see [`entry::EntryMetadata`].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uri: Option<String>,
+        /// Upstream mirror if recorded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mirror: Option<String>,
+        integrity: Integrity,
+        entry: Bytes,
+    },
+    /// Responses under `key` vary on `vary` headers.
+    Vary {
+        key: String,
+        vary: Vec<String>,
+    },
+    End {
+        records: u64,
+    },
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    ReadCache(cacache::Error),
+    WriteCache(cacache::Error),
+    Encode(ciborium::ser::Error<io::Error>),
+    Decode(ciborium::de::Error<io::Error>),
+    InvalidFormat(String),
+    UnexpectedAuthority(String),
+    /// Content of entry with the key does not match its digest.
+    Integrity(String),
+}
+impl Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ReadCache(e) => write!(f, "failed to read cache: {e}"),
+            Self::WriteCache(e) => write!(f, "failed to write cache: {e}"),
+            Self::Encode(e) => write!(f, "failed to write archive: {e}"),
+            Self::Decode(e) => write!(f, "failed to read archive: {e}"),
+            Self::InvalidFormat(e) => write!(f, "invalid archive: {e}"),
+            Self::UnexpectedAuthority(a) => write!(f, "archive is of unexpected host {a}"),
+            Self::Integrity(k) => write!(f, "integrity check failed for entry {k:?}"),
+        }
+    }
+}
+impl std::error::Error for ArchiveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::ReadCache(e) => Some(e),
+            Self::WriteCache(e) => Some(e),
+            Self::Encode(e) => Some(e),
+            Self::Decode(e) => Some(e),
+            Self::InvalidFormat(_) | Self::UnexpectedAuthority(_) | Self::Integrity(_) => None,
+        }
+    }
+}
+
+/// Entries written to an archive.
+#[derive(Debug, Clone, Default)]
+pub struct ExportFilter {
+    /// Only entries with key starting with it.
+    pub prefix: String,
+    /// Only entries that are still fresh.
+    pub fresh: bool,
+}
+
+/// How to handle entries of the archive that are already cached.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Existing {
+    /// Keep cached entries.
+    #[default]
+    Keep,
+    /// Replace cached entries with the archived ones.
+    Replace,
+    /// Replace cached entries if the archived response is received more recently.
+    Newer,
+}
+
+#[derive(Debug, Default)]
+pub struct ArchiveStats {
+    pub entries: usize,
+    pub skipped: usize,
+}
+
+/// Write entries of cache `root` for `authority` selected by `filter` to `writer`.
+///
+/// Compressed bodies are not exported, they are created again when requested.
+pub fn export(
+    root: &Path,
+    authority: &Authority,
+    filter: &ExportFilter,
+    mut writer: impl Write,
+) -> Result<ArchiveStats, ArchiveError> {
+    let now = SystemTime::now();
+    let mut stats = ArchiveStats::default();
+    ciborium::into_writer(
+        &Header {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            authority: authority.to_string(),
+        },
+        &mut writer,
+    )
+    .map_err(ArchiveError::Encode)?;
+    for md in gc::list_index(root).map_err(ArchiveError::ReadCache)? {
+        if !md.key.starts_with(&filter.prefix) || md.key.starts_with("encoded:") {
+            continue;
+        }
+        let record = match entry::read_vary_index(&md) {
+            Some(vary) => Record::Vary {
+                key: md.key,
+                vary: vary.iter().map(|h| h.to_string()).collect(),
+            },
+            None => {
+                let stored = match entry::decode(root, &md) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        tracing::warn!(key = md.key, "invalid entry skipped: {e}");
+                        stats.skipped += 1;
+                        continue;
+                    }
+                };
+                if filter.fresh && stored.policy().is_stale(now) {
+                    tracing::debug!(key = md.key, "entry is stale, skipped");
+                    stats.skipped += 1;
+                    continue;
+                }
+                let (policy, body, digest) = match stored {
+                    Stored::Current(e) => (
+                        e.policy,
+                        entry::read_body(root, &e.integrity).map_err(ArchiveError::ReadCache)?,
+                        e.integrity,
+                    ),
+                    Stored::V0 { policy, body } => {
+                        let digest = Integrity::from(&body);
+                        (policy, body, digest)
+                    }
+                };
+                let metadata = entry::EntryMetadata::read(&md);
+                let mut data = Vec::new();
+                ciborium::into_writer(
+                    &ArchivedEntry {
+                        policy,
+                        body,
+                        digest: Some(digest),
+                    },
+                    &mut data,
+                )
+                .map_err(ArchiveError::Encode)?;
+                Record::Entry {
+                    uri: metadata.uri,
+                    mirror: metadata.mirror,
+                    key: md.key,
+                    integrity: Integrity::from(&data),
+                    entry: Bytes::from(data),
+                }
+            }
+        };
+        ciborium::into_writer(&record, &mut writer).map_err(ArchiveError::Encode)?;
+        stats.entries += 1;
+    }
+    ciborium::into_writer(
+        &Record::End {
+            records: stats.entries as u64,
+        },
+        &mut writer,
+    )
+    .map_err(ArchiveError::Encode)?;
+    writer
+        .flush()
+        .map_err(|e| ArchiveError::Encode(ciborium::ser::Error::Io(e)))?;
+    Ok(stats)
+}
+
+/// Whether archived entry should be written when `key` is already cached.
+fn should_replace(
+    root: &Path,
+    key: &str,
+    archived: &CachePolicy,
+    existing: Existing,
+) -> Result<bool, ArchiveError> {
+    let md = match cacache::index::find(root, key).map_err(ArchiveError::ReadCache)? {
+        Some(md) => md,
+        None => return Ok(true),
+    };
+    match existing {
+        Existing::Keep => Ok(false),
+        Existing::Replace => Ok(true),
+        Existing::Newer => {
+            let now = SystemTime::now();
+            // a vary index has no policy, it is written with the first variant
+            let cached_age = if entry::read_vary_index(&md).is_some() {
+                let indexed = SystemTime::UNIX_EPOCH + Duration::from_millis(md.time as u64);
+                now.duration_since(indexed).unwrap_or_default()
+            } else {
+                match entry::decode(root, &md) {
+                    Ok(cached) => cached.policy().age(now),
+                    Err(e) => {
+                        tracing::warn!(key, "cached entry is invalid, replaced: {e}");
+                        return Ok(true);
+                    }
+                }
+            };
+            Ok(archived.age(now) < cached_age)
+        }
+    }
+}
+
+/// Verified record of an archive being imported.
+enum Imported {
+    Entry {
+        key: String,
+        metadata: Box<entry::EntryMetadata>,
+        body: Bytes,
+    },
+    Vary {
+        key: String,
+        vary: Vec<HeaderName>,
+    },
+}
+
+/// Decode and verify records of archive of `authority` in `reader`, calling
+/// `f` with each of them.
+fn read_archive(
+    mut reader: impl Read,
+    authority: &Authority,
+    mut f: impl FnMut(Imported) -> Result<(), ArchiveError>,
+) -> Result<(), ArchiveError> {
+    let header: Header = ciborium::from_reader(&mut reader).map_err(ArchiveError::Decode)?;
+    if header.format != FORMAT {
+        return Err(ArchiveError::InvalidFormat(format!(
+            "unknown format {:?}",
+            header.format
+        )));
+    }
+    if !(1..=VERSION).contains(&header.version) {
+        return Err(ArchiveError::InvalidFormat(format!(
+            "unsupported version {}",
+            header.version
+        )));
+    }
+    if header.authority != authority.as_str() {
+        return Err(ArchiveError::UnexpectedAuthority(header.authority));
+    }
+    let mut records = 0;
+    loop {
+        let imported = match ciborium::from_reader(&mut reader).map_err(ArchiveError::Decode)? {
+            Record::Entry {
+                key,
+                uri,
+                mirror,
+                integrity,
+                entry,
+            } => {
+                if integrity.check(&entry).is_err() {
+                    return Err(ArchiveError::Integrity(key));
+                }
+                let decoded = ciborium::from_reader::<ArchivedEntry, _>(entry.as_ref())
+                    .map_err(ArchiveError::Decode)?;
+                match &decoded.digest {
+                    Some(d) if d.check(&decoded.body).is_err() => {
+                        return Err(ArchiveError::Integrity(key));
+                    }
+                    None if header.version > 1 => {
+                        return Err(ArchiveError::InvalidFormat(format!(
+                            "missing digest of entry {key:?}"
+                        )));
+                    }
+                    _ => {}
+                }
+                Imported::Entry {
+                    key,
+                    metadata: Box::new(entry::EntryMetadata {
+                        uri,
+                        mirror,
+                        policy: Some(decoded.policy),
+                    }),
+                    body: decoded.body,
+                }
+            }
+            Record::Vary { key, vary } => {
+                let vary = vary
+                    .iter()
+                    .map(|h| HeaderName::try_from(h.as_str()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| {
+                        ArchiveError::InvalidFormat(format!("invalid vary header: {e}"))
+                    })?;
+                Imported::Vary { key, vary }
+            }
+            Record::End { records: expected } => {
+                if expected != records {
+                    return Err(ArchiveError::InvalidFormat(format!(
+                        "expect {expected} records, found {records}"
+                    )));
+                }
+                return Ok(());
+            }
+        };
+        records += 1;
+        f(imported)?;
+    }
+}
+
+/// Write verified `record` into cache `root`.
+fn write_record(
+    root: &Path,
+    existing: Existing,
+    record: Imported,
+    stats: &mut ArchiveStats,
+) -> Result<(), ArchiveError> {
+    match record {
+        Imported::Entry {
+            key,
+            metadata,
+            body,
+        } => {
+            let policy = metadata.policy.as_ref().expect("archived entry has policy");
+            if !should_replace(root, &key, policy, existing)? {
+                tracing::debug!(key, "entry is already cached, skipped");
+                stats.skipped += 1;
+                return Ok(());
+            }
+            entry::write_entry(root, &key, &metadata, &body).map_err(ArchiveError::WriteCache)?;
+        }
+        Imported::Vary { key, vary } => {
+            let cached = cacache::index::find(root, &key).map_err(ArchiveError::ReadCache)?;
+            if cached.is_some() && !matches!(existing, Existing::Replace) {
+                stats.skipped += 1;
+                return Ok(());
+            }
+            entry::write_vary_index(root, &key, &vary).map_err(ArchiveError::WriteCache)?;
+        }
+    }
+    stats.entries += 1;
+    Ok(())
+}
+
+/// Read archive of `authority` from `reader` into cache `root`.
+///
+/// The whole archive is read and verified first, each entry against its
+/// digest and the digest of its body, and the number of records against
+/// the end of the archive. Nothing is written if any of them fails.
+pub fn import(
+    root: &Path,
+    authority: &Authority,
+    existing: Existing,
+    mut reader: impl Read + Seek,
+) -> Result<ArchiveStats, ArchiveError> {
+    read_archive(&mut reader, authority, |_| Ok(()))?;
+    reader
+        .rewind()
+        .map_err(|e| ArchiveError::Decode(ciborium::de::Error::Io(e)))?;
+    let mut stats = ArchiveStats::default();
+    read_archive(&mut reader, authority, |record| {
+        write_record(root, existing, record, &mut stats)
+    })?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use http::header;
+    use http_cache_semantics::CacheOptions;
+
+    use super::*;
+
+    fn authority() -> Authority {
+        Authority::from_static("example.com")
+    }
+
+    /// Policy of response received `age` seconds ago.
+    fn policy(age: u64) -> CachePolicy {
+        let req = http::Request::get("https://example.com/lib.js")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        let res = http::Response::builder()
+            .header(header::CACHE_CONTROL, "max-age=3600")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        let received = SystemTime::now() - Duration::from_secs(age);
+        CachePolicy::new_options(&req, &res, received, CacheOptions::default())
+    }
+
+    fn cache(root: &Path, key: &str, age: u64, body: &[u8]) {
+        let metadata = entry::EntryMetadata {
+            uri: None,
+            mirror: None,
+            policy: Some(policy(age)),
+        };
+        entry::write_entry(root, key, &metadata, body).unwrap();
+    }
+
+    fn cached_body(root: &Path, key: &str) -> Bytes {
+        let md = cacache::index::find(root, key).unwrap().unwrap();
+        match entry::decode(root, &md).unwrap() {
+            Stored::Current(e) => entry::read_body(root, &e.integrity).unwrap(),
+            Stored::V0 { .. } => panic!("entry of version 0 is imported"),
+        }
+    }
+
+    fn record(key: &str, age: u64, body: &[u8], digest: Option<Integrity>) -> Record {
+        let mut data = Vec::new();
+        let entry = ArchivedEntry {
+            policy: policy(age),
+            body: Bytes::copy_from_slice(body),
+            digest,
+        };
+        ciborium::into_writer(&entry, &mut data).unwrap();
+        Record::Entry {
+            key: key.to_string(),
+            uri: None,
+            mirror: None,
+            integrity: Integrity::from(&data),
+            entry: Bytes::from(data),
+        }
+    }
+
+    fn archived(key: &str, age: u64, body: &[u8]) -> Record {
+        record(key, age, body, Some(Integrity::from(body)))
+    }
+
+    fn archive(version: u32, records: &[Record]) -> Cursor<Vec<u8>> {
+        let mut data = Vec::new();
+        let header = Header {
+            format: FORMAT.to_string(),
+            version,
+            authority: authority().to_string(),
+        };
+        ciborium::into_writer(&header, &mut data).unwrap();
+        for record in records {
+            ciborium::into_writer(record, &mut data).unwrap();
+        }
+        let end = Record::End {
+            records: records.len() as u64,
+        };
+        ciborium::into_writer(&end, &mut data).unwrap();
+        Cursor::new(data)
+    }
+
+    #[test]
+    fn export_and_import() {
+        let source = tempfile::tempdir().unwrap();
+        cache(source.path(), "a", 0, b"a");
+        entry::write_vary_index(source.path(), "b", &[header::ACCEPT_ENCODING]).unwrap();
+        let mut data = Vec::new();
+        let stats = export(
+            source.path(),
+            &authority(),
+            &ExportFilter::default(),
+            &mut data,
+        )
+        .unwrap();
+        assert_eq!(stats.entries, 2);
+
+        let dir = tempfile::tempdir().unwrap();
+        let stats = import(dir.path(), &authority(), Existing::Keep, Cursor::new(data)).unwrap();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(cached_body(dir.path(), "a"), "a");
+        let md = cacache::index::find(dir.path(), "b").unwrap().unwrap();
+        assert_eq!(
+            entry::read_vary_index(&md),
+            Some(vec![header::ACCEPT_ENCODING])
+        );
+    }
+
+    #[test]
+    fn import_checks_integrity() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tampered = archived("b", 0, b"b");
+        if let Record::Entry { integrity, .. } = &mut tampered {
+            *integrity = Integrity::from(b"other");
+        }
+        let reader = archive(VERSION, &[archived("a", 0, b"a"), tampered]);
+        let err = import(dir.path(), &authority(), Existing::Keep, reader).unwrap_err();
+        assert!(matches!(err, ArchiveError::Integrity(k) if k == "b"));
+        // nothing is written before the whole archive is verified
+        assert!(cacache::index::find(dir.path(), "a").unwrap().is_none());
+
+        let reader = archive(
+            VERSION,
+            &[record("a", 0, b"a", Some(Integrity::from(b"other")))],
+        );
+        let err = import(dir.path(), &authority(), Existing::Keep, reader).unwrap_err();
+        assert!(matches!(err, ArchiveError::Integrity(k) if k == "a"));
+    }
+
+    #[test]
+    fn digest_required_since_version_2() {
+        let dir = tempfile::tempdir().unwrap();
+        let reader = archive(VERSION, &[record("a", 0, b"a", None)]);
+        let err = import(dir.path(), &authority(), Existing::Keep, reader).unwrap_err();
+        assert!(matches!(err, ArchiveError::InvalidFormat(_)));
+
+        let reader = archive(1, &[record("a", 0, b"a", None)]);
+        import(dir.path(), &authority(), Existing::Keep, reader).unwrap();
+        assert_eq!(cached_body(dir.path(), "a"), "a");
+
+        let reader = archive(VERSION + 1, &[]);
+        let err = import(dir.path(), &authority(), Existing::Keep, reader).unwrap_err();
+        assert!(matches!(err, ArchiveError::InvalidFormat(_)));
+    }
+
+    /// Bodies of "older" and "newer" after importing archived entries
+    /// received between them with `existing`.
+    fn import_existing(existing: Existing) -> (ArchiveStats, Bytes, Bytes) {
+        let dir = tempfile::tempdir().unwrap();
+        cache(dir.path(), "older", 120, b"cached");
+        cache(dir.path(), "newer", 10, b"cached");
+        let reader = archive(
+            VERSION,
+            &[
+                archived("older", 60, b"archived"),
+                archived("newer", 60, b"archived"),
+                archived("missing", 60, b"archived"),
+            ],
+        );
+        let stats = import(dir.path(), &authority(), existing, reader).unwrap();
+        assert_eq!(cached_body(dir.path(), "missing"), "archived");
+        (
+            stats,
+            cached_body(dir.path(), "older"),
+            cached_body(dir.path(), "newer"),
+        )
+    }
+
+    #[test]
+    fn keep_existing() {
+        let (stats, older, newer) = import_existing(Existing::Keep);
+        assert_eq!((stats.entries, stats.skipped), (1, 2));
+        assert_eq!(
+            (older.as_ref(), newer.as_ref()),
+            (&b"cached"[..], &b"cached"[..])
+        );
+    }
+
+    #[test]
+    fn replace_existing() {
+        let (stats, older, newer) = import_existing(Existing::Replace);
+        assert_eq!((stats.entries, stats.skipped), (3, 0));
+        assert_eq!(
+            (older.as_ref(), newer.as_ref()),
+            (&b"archived"[..], &b"archived"[..])
+        );
+    }
+
+    #[test]
+    fn replace_existing_if_newer() {
+        let (stats, older, newer) = import_existing(Existing::Newer);
+        assert_eq!((stats.entries, stats.skipped), (2, 1));
+        assert_eq!(
+            (older.as_ref(), newer.as_ref()),
+            (&b"archived"[..], &b"cached"[..])
+        );
+    }
+
+    /// Vary index of `key` written `age` seconds ago.
+    fn vary_index(root: &Path, key: &str, age: u64) {
+        let time = SystemTime::now() - Duration::from_secs(age);
+        let opts = cacache::WriteOpts::new()
+            .integrity(Integrity::from(b""))
+            .time(
+                time.duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis(),
+            )
+            .metadata(serde_json::json!({ "vary": ["accept-encoding"] }));
+        cacache::index::insert(root, key, opts).unwrap();
+    }
+
+    #[test]
+    fn replace_vary_index_if_newer() {
+        let dir = tempfile::tempdir().unwrap();
+        vary_index(dir.path(), "older", 120);
+        vary_index(dir.path(), "newer", 10);
+        let reader = archive(
+            VERSION,
+            &[
+                archived("older", 60, b"archived"),
+                archived("newer", 60, b"archived"),
+            ],
+        );
+        let stats = import(dir.path(), &authority(), Existing::Newer, reader).unwrap();
+        assert_eq!((stats.entries, stats.skipped), (1, 1));
+        assert_eq!(cached_body(dir.path(), "older"), "archived");
+        let md = cacache::index::find(dir.path(), "newer").unwrap().unwrap();
+        assert!(entry::read_vary_index(&md).is_some());
+    }
+}
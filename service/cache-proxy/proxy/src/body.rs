@@ -0,0 +1,407 @@
+use std::{
+    future::Future,
+    io::{self, Read, Seek},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use http_body_util::Full;
+use hyper::body::{Body, Bytes, Frame, Incoming, SizeHint};
+use ssri::Integrity;
+use tokio::sync::{mpsc, oneshot};
+use tower_http::{decompression::DecompressionBody, BoxError};
+use tracing::Instrument;
+
+use crate::{entry::BackgroundWriter, gc, metrics::CountBytes, range::Part, ClassifyEos};
+
+pub type ForwardedBody = tower_http::trace::ResponseBody<Incoming, ClassifyEos, CountBytes>;
+pub(crate) type UpstreamRespBody = DecompressionBody<ForwardedBody>;
+
+/// Upstream response body that is written to the cache while it is read.
+///
+/// The cache entry is only committed after the whole body has been received,
+/// if the body fails or is dropped early, nothing is stored.
+pub(crate) struct TeeBody {
+    inner: Pin<Box<UpstreamRespBody>>,
+    writer: Option<BackgroundWriter>,
+    committed: Option<oneshot::Receiver<(Integrity, u64)>>,
+}
+impl TeeBody {
+    /// Chunks queued for the client, upstream is read as fast as the client
+    /// once it is full.
+    const QUEUE: usize = 16;
+    /// Time the client may not read a chunk, before the rest of the body is
+    /// read from cache once it is stored.
+    const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub(crate) fn new(inner: UpstreamRespBody, writer: BackgroundWriter) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            writer: Some(writer),
+            committed: None,
+        }
+    }
+    /// Read the body into cache in a task, which continues if the client is
+    /// gone, so that requests waiting for the entry still get it.
+    ///
+    /// The body is sent to the returned [`FillingBody`] with backpressure. If
+    /// the client stalls for [`Self::CLIENT_TIMEOUT`], it is detached and
+    /// continues from cache in `root` once the entry is stored.
+    pub(crate) fn spawn(mut self, root: Arc<Path>) -> FillingBody {
+        let (tx, rx) = mpsc::channel(Self::QUEUE);
+        let (done_tx, done) = oneshot::channel();
+        let size = self.inner.size_hint().exact();
+        let fill = async move {
+            let mut tx = Some(tx);
+            while let Some(frame) =
+                std::future::poll_fn(|cx| Pin::new(&mut self).poll_frame(cx)).await
+            {
+                let data = match frame {
+                    Ok(f) => f.into_data().ok(),
+                    Err(e) => {
+                        if let Some(tx) = tx.take() {
+                            let _ = tx.try_send(Err(e));
+                        }
+                        let _ = done_tx.send(None);
+                        return;
+                    }
+                };
+                if let (Some(data), Some(sender)) = (data, &tx) {
+                    match tokio::time::timeout(Self::CLIENT_TIMEOUT, sender.send(Ok(data))).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(_)) => {
+                            tracing::debug!("client is gone, filling cache in background");
+                            tx = None;
+                        }
+                        Err(_) => {
+                            tracing::debug!("client stalled, send rest from cache");
+                            tx = None;
+                        }
+                    }
+                }
+            }
+            let forwarded = match tx {
+                Some(_) => Some(Forwarded::All(self.committed.take())),
+                None => self.stored().await.map(|(i, s)| Forwarded::Stored(i, s)),
+            };
+            let _ = done_tx.send(forwarded);
+        };
+        tokio::spawn(fill.in_current_span());
+        FillingBody {
+            rx,
+            done: Some(done),
+            rest: None,
+            root,
+            received: 0,
+            size,
+        }
+    }
+    /// Integrity and size of the body once it is stored, `None` if it is
+    /// not stored.
+    async fn stored(&mut self) -> Option<(Integrity, u64)> {
+        self.committed.take()?.await.ok()
+    }
+    /// Commit entry if `commit` is true, otherwise discard it. Requests
+    /// waiting for this entry are woken up once the writer is finished.
+    fn finish(&mut self, commit: bool) {
+        if let Some(w) = self.writer.take().filter(|_| commit) {
+            self.committed = Some(w.commit());
+        }
+    }
+}
+impl Body for TeeBody {
+    type Data = Bytes;
+    type Error = tower_http::BoxError;
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        // upstream is only read as fast as the body is written to disk
+        if let Some(w) = this.writer.as_mut() {
+            if !ready!(w.poll_reserve(cx)) {
+                // failure is logged by the writer
+                this.finish(false);
+            }
+        }
+        let frame = ready!(this.inner.as_mut().poll_frame(cx));
+        match &frame {
+            Some(Ok(f)) => {
+                if let (Some(data), Some(w)) = (f.data_ref(), this.writer.as_mut()) {
+                    // failure is logged by the writer
+                    if !w.write(data.clone()) {
+                        this.finish(false);
+                    }
+                }
+                // server stops polling once `Content-Length` bytes are sent
+                if this.inner.is_end_stream()
+                    || this.writer.as_ref().is_some_and(|w| w.is_complete())
+                {
+                    this.finish(true);
+                }
+            }
+            Some(Err(e)) => {
+                if let Some(w) = &this.writer {
+                    tracing::warn!(key = w.key(), "upstream body failed, entry discarded: {e}");
+                }
+                this.finish(false);
+            }
+            None => this.finish(true),
+        }
+        Poll::Ready(frame)
+    }
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+impl Drop for TeeBody {
+    fn drop(&mut self) {
+        let complete = self.writer.as_ref().is_some_and(|w| w.is_complete());
+        if let Some(w) = self.writer.as_ref().filter(|_| !complete) {
+            tracing::warn!(
+                key = w.key(),
+                "response body not completed, entry discarded"
+            );
+        }
+        self.finish(complete);
+    }
+}
+
+/// How a [`FillingBody`] is completed after the fill task stops sending it.
+enum Forwarded {
+    /// The whole body is sent, with the receiver of [`BackgroundWriter::commit`]
+    /// if it is committed.
+    All(Option<oneshot::Receiver<(Integrity, u64)>>),
+    /// The body is stored with integrity and size, the rest is read from cache.
+    Stored(Integrity, u64),
+}
+
+/// Upstream response body sent to client while it is filled by
+/// [`TeeBody::spawn`].
+pub struct FillingBody {
+    rx: mpsc::Receiver<Result<Bytes, BoxError>>,
+    /// `None` if the body is not stored.
+    done: Option<oneshot::Receiver<Option<Forwarded>>>,
+    rest: Option<StoredBody>,
+    root: Arc<Path>,
+    received: u64,
+    size: Option<u64>,
+}
+impl FillingBody {
+    /// Wait until the body is filled without reading it, returns whether
+    /// the entry is committed.
+    pub(crate) async fn drain(mut self) -> bool {
+        self.rx.close();
+        let Some(done) = self.done.take() else {
+            return false;
+        };
+        match done.await {
+            Ok(Some(Forwarded::Stored(_, _))) => true,
+            Ok(Some(Forwarded::All(Some(committed)))) => committed.await.is_ok(),
+            _ => false,
+        }
+    }
+}
+impl Body for FillingBody {
+    type Data = Bytes;
+    type Error = BoxError;
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(rest) = &mut this.rest {
+                return Pin::new(rest).poll_frame(cx);
+            }
+            if let Some(chunk) = ready!(this.rx.poll_recv(cx)) {
+                if let Ok(data) = &chunk {
+                    this.received += data.len() as u64;
+                }
+                return Poll::Ready(Some(chunk.map(Frame::data)));
+            }
+            let done = match &mut this.done {
+                Some(done) => ready!(Pin::new(done).poll(cx)),
+                None => return Poll::Ready(None),
+            };
+            this.done = None;
+            match done {
+                Ok(Some(Forwarded::All(_))) => return Poll::Ready(None),
+                Ok(Some(Forwarded::Stored(integrity, size))) => {
+                    this.rest = Some(StoredBody::open_range(
+                        Arc::clone(&this.root),
+                        integrity,
+                        this.received,
+                        size.saturating_sub(this.received),
+                    ));
+                }
+                _ => return Poll::Ready(Some(Err("failed to fill cache entry".into()))),
+            }
+        }
+    }
+    fn size_hint(&self) -> SizeHint {
+        match (&self.rest, self.size) {
+            (Some(rest), _) => rest.size_hint(),
+            (None, Some(size)) => SizeHint::with_exact(size.saturating_sub(self.received)),
+            (None, None) => SizeHint::default(),
+        }
+    }
+}
+
+/// Body of a cache entry, read from disk in the blocking thread pool while
+/// it is sent.
+///
+/// Integrity of the whole body is checked after it is read, the body fails if
+/// it doesn't match. Ranges of the body can't be checked.
+pub struct StoredBody {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+    size: u64,
+}
+impl StoredBody {
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    pub(crate) fn open(root: Arc<Path>, integrity: Integrity, size: u64) -> Self {
+        Self::spawn(size, move |tx| {
+            let mut reader = cacache::SyncReader::open_hash(&root, integrity)?;
+            if send(&mut reader, u64::MAX, tx)? {
+                reader.check()?;
+            }
+            Ok(())
+        })
+    }
+    /// Read `len` bytes of the body with `integrity` from `offset`.
+    pub(crate) fn open_range(root: Arc<Path>, integrity: Integrity, offset: u64, len: u64) -> Self {
+        Self::open_parts(root, integrity, vec![Part::Stored { offset, len }])
+    }
+    /// Send `parts` of a partial response, seeking to each range of the body
+    /// with `integrity` so that only the requested bytes are read.
+    pub(crate) fn open_parts(root: Arc<Path>, integrity: Integrity, parts: Vec<Part>) -> Self {
+        let size = parts.iter().map(Part::len).sum();
+        Self::spawn(size, move |tx| {
+            let path = gc::content_path(&root, &integrity);
+            let io_err =
+                |e| cacache::Error::IoError(e, format!("failed to read {}", path.display()));
+            let mut file = None;
+            for part in parts {
+                match part {
+                    Part::Literal(b) => {
+                        if tx.blocking_send(Ok(b)).is_err() {
+                            break;
+                        }
+                    }
+                    Part::Stored { offset, len } => {
+                        let f = match &mut file {
+                            Some(f) => f,
+                            None => file.insert(std::fs::File::open(&path).map_err(io_err)?),
+                        };
+                        f.seek(io::SeekFrom::Start(offset)).map_err(io_err)?;
+                        if !send(f, len, tx)? {
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+    /// Send body of `size` bytes read by `read` in the blocking thread pool.
+    fn spawn<F>(size: u64, read: F) -> Self
+    where
+        F: FnOnce(&mpsc::Sender<io::Result<Bytes>>) -> Result<(), cacache::Error> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(4);
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _enter = span.enter();
+            if let Err(e) = read(&tx) {
+                tracing::error!("failed to read cached body: {e}");
+                let _ = tx.blocking_send(Err(io::Error::other(e)));
+            }
+        });
+        Self { rx, size }
+    }
+}
+
+/// Send at most `limit` bytes of `reader` to `tx`, returns `false` if the
+/// client is gone.
+fn send(
+    reader: &mut impl Read,
+    mut limit: u64,
+    tx: &mpsc::Sender<io::Result<Bytes>>,
+) -> Result<bool, cacache::Error> {
+    while limit > 0 {
+        let mut buf = vec![0; limit.min(StoredBody::CHUNK_SIZE as u64) as usize];
+        let n = reader
+            .read(&mut buf)
+            .map_err(|e| cacache::Error::IoError(e, "failed to read cached body".into()))?;
+        if n == 0 {
+            break;
+        }
+        buf.truncate(n);
+        limit -= n as u64;
+        if tx.blocking_send(Ok(Bytes::from(buf))).is_err() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+impl Body for StoredBody {
+    type Data = Bytes;
+    type Error = tower_http::BoxError;
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        Poll::Ready(ready!(this.rx.poll_recv(cx)).map(|r| r.map(Frame::data).map_err(Into::into)))
+    }
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.size)
+    }
+}
+
+/// Response body returned by [`CacheProxy`](crate::CacheProxy).
+#[pin_project::pin_project(project = CachedBodyProj)]
+pub enum CachedBody {
+    Forwarded(#[pin] ForwardedBody),
+    Full(#[pin] Full<Bytes>),
+    Filling(#[pin] FillingBody),
+    Stored(#[pin] StoredBody),
+}
+impl Body for CachedBody {
+    type Data = Bytes;
+    type Error = tower_http::BoxError;
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.project() {
+            CachedBodyProj::Forwarded(b) => b.poll_frame(cx).map_err(Into::into),
+            CachedBodyProj::Full(b) => b.poll_frame(cx).map_err(|e| match e {}),
+            CachedBodyProj::Filling(b) => b.poll_frame(cx),
+            CachedBodyProj::Stored(b) => b.poll_frame(cx),
+        }
+    }
+    fn is_end_stream(&self) -> bool {
+        match self {
+            Self::Forwarded(b) => b.is_end_stream(),
+            Self::Full(b) => b.is_end_stream(),
+            Self::Filling(b) => b.is_end_stream(),
+            Self::Stored(b) => b.is_end_stream(),
+        }
+    }
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            Self::Forwarded(b) => b.size_hint(),
+            Self::Full(b) => b.size_hint(),
+            Self::Filling(b) => b.size_hint(),
+            Self::Stored(b) => b.size_hint(),
+        }
+    }
+}
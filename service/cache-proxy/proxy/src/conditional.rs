@@ -0,0 +1,134 @@
+use http::{header, HeaderMap, StatusCode};
+
+fn parse_date(v: &str) -> Option<std::time::SystemTime> {
+    httpdate::parse_http_date(v.trim()).ok()
+}
+
+fn get(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Compare entity tags with weak comparison.
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim().trim_start_matches("W/") == b.trim().trim_start_matches("W/")
+}
+
+/// Whether the stored response `resp` is not modified according to
+/// validators in client request headers `req`.
+///
+/// `If-Modified-Since` is only used when there is no `If-None-Match`.
+pub(crate) fn is_not_modified(req: &HeaderMap, resp: &http::response::Parts) -> bool {
+    if resp.status != StatusCode::OK {
+        return false;
+    }
+    if req.contains_key(header::IF_NONE_MATCH) {
+        let etag = get(&resp.headers, header::ETAG);
+        req.get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .any(|t| t == "*" || etag.is_some_and(|e| weak_eq(e, t)))
+    } else {
+        match (
+            get(req, header::IF_MODIFIED_SINCE).and_then(parse_date),
+            get(&resp.headers, header::LAST_MODIFIED).and_then(parse_date),
+        ) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+}
+
+/// Turn stored response `pts` into a `304 Not Modified` response.
+pub(crate) fn not_modified(mut pts: http::response::Parts) -> http::response::Parts {
+    pts.status = StatusCode::NOT_MODIFIED;
+    for h in [
+        header::CONTENT_LENGTH,
+        header::CONTENT_TYPE,
+        header::CONTENT_RANGE,
+        header::ACCEPT_RANGES,
+    ] {
+        pts.headers.remove(h);
+    }
+    pts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETAG: &str = "\"v1\"";
+    const MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    fn stored(status: StatusCode) -> http::response::Parts {
+        http::Response::builder()
+            .status(status)
+            .header(header::ETAG, ETAG)
+            .header(header::LAST_MODIFIED, MODIFIED)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    fn request(headers: &[(header::HeaderName, &str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(h, v)| (h.clone(), v.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn if_none_match() {
+        let resp = stored(StatusCode::OK);
+        let not_modified = |v| is_not_modified(&request(&[(header::IF_NONE_MATCH, v)]), &resp);
+        assert!(not_modified("\"v1\""));
+        assert!(not_modified("W/\"v1\""));
+        assert!(not_modified("\"v0\", \"v1\""));
+        assert!(not_modified("*"));
+        assert!(!not_modified("\"v2\""));
+        assert!(!not_modified("\"v0\", \"v2\""));
+    }
+
+    #[test]
+    fn if_none_match_without_etag() {
+        let mut resp = stored(StatusCode::OK);
+        resp.headers.remove(header::ETAG);
+        assert!(!is_not_modified(
+            &request(&[(header::IF_NONE_MATCH, ETAG)]),
+            &resp
+        ));
+        assert!(is_not_modified(
+            &request(&[(header::IF_NONE_MATCH, "*")]),
+            &resp
+        ));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let resp = stored(StatusCode::OK);
+        let not_modified = |v| is_not_modified(&request(&[(header::IF_MODIFIED_SINCE, v)]), &resp);
+        assert!(not_modified(MODIFIED));
+        assert!(not_modified("Thu, 22 Oct 2015 07:28:00 GMT"));
+        assert!(!not_modified("Tue, 20 Oct 2015 07:28:00 GMT"));
+        assert!(!not_modified("yesterday"));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let resp = stored(StatusCode::OK);
+        let req = request(&[
+            (header::IF_NONE_MATCH, "\"v2\""),
+            (header::IF_MODIFIED_SINCE, MODIFIED),
+        ]);
+        assert!(!is_not_modified(&req, &resp));
+    }
+
+    #[test]
+    fn only_ok_responses() {
+        let req = request(&[(header::IF_NONE_MATCH, ETAG)]);
+        assert!(!is_not_modified(&req, &stored(StatusCode::NOT_FOUND)));
+        assert!(!is_not_modified(&request(&[]), &stored(StatusCode::OK)));
+    }
+}
@@ -0,0 +1,249 @@
+use std::io::{self, Write};
+
+use http::{header, HeaderMap, HeaderValue};
+use ssri::Integrity;
+
+/// Bodies smaller than this are not worth compressing.
+const MIN_COMPRESS_SIZE: usize = 1024;
+
+/// Content coding the proxy can compress cached bodies with, declared in
+/// order of preference when the client accepts several with the same weight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+impl Encoding {
+    const SUPPORTED: [Encoding; 3] = [Self::Brotli, Self::Zstd, Self::Gzip];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+    /// Encoding preferred by client with `Accept-Encoding` in `headers`.
+    pub(crate) fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let better = |best: Option<(Self, f32)>, enc: Self, q: f32| {
+            best.is_none_or(|(e, b)| q > b || (q == b && enc < e))
+        };
+        let mut best: Option<(Self, f32)> = None;
+        let mut wildcard = None;
+        let mut listed = Vec::new();
+        for item in headers
+            .get_all(header::ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+        {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or("").trim();
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if coding == "*" {
+                wildcard = Some(q);
+                continue;
+            }
+            if let Some(enc) = Self::SUPPORTED
+                .into_iter()
+                .find(|e| coding.eq_ignore_ascii_case(e.name()))
+            {
+                listed.push(enc);
+                if q > 0.0 && better(best, enc, q) {
+                    best = Some((enc, q));
+                }
+            }
+        }
+        if let Some(q) = wildcard.filter(|q| *q > 0.0) {
+            if let Some(enc) = Self::SUPPORTED.into_iter().find(|e| !listed.contains(e)) {
+                if better(best, enc, q) {
+                    best = Some((enc, q));
+                }
+            }
+        }
+        best.map(|(e, _)| e)
+    }
+    /// Writer compressing data in this encoding into `w`.
+    pub(crate) fn encoder<W: Write>(self, w: W) -> io::Result<Encoder<W>> {
+        Ok(match self {
+            Self::Brotli => {
+                Encoder::Brotli(Box::new(brotli::CompressorWriter::new(w, 4096, 9, 22)))
+            }
+            Self::Zstd => Encoder::Zstd(zstd::Encoder::new(w, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+            Self::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                w,
+                flate2::Compression::default(),
+            )),
+        })
+    }
+}
+
+/// Writer compressing data written to it, see [`Encoding::encoder`].
+pub(crate) enum Encoder<W: Write> {
+    Brotli(Box<brotli::CompressorWriter<W>>),
+    Zstd(zstd::Encoder<'static, W>),
+    Gzip(flate2::write::GzEncoder<W>),
+}
+impl<W: Write> Encoder<W> {
+    /// Write the end of compressed data and return the inner writer.
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Brotli(mut w) => {
+                w.flush()?;
+                Ok(w.into_inner())
+            }
+            Self::Zstd(w) => w.finish(),
+            Self::Gzip(w) => w.finish(),
+        }
+    }
+}
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Brotli(w) => w.write(buf),
+            Self::Zstd(w) => w.write(buf),
+            Self::Gzip(w) => w.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Brotli(w) => w.flush(),
+            Self::Zstd(w) => w.flush(),
+            Self::Gzip(w) => w.flush(),
+        }
+    }
+}
+
+/// Cache key of `body` with `digest` compressed in `enc`.
+///
+/// Compressed bodies are addressed by digest of the identity body, so they
+/// never get out of date and are shared by entries with the same content.
+pub(crate) fn encoded_key(enc: Encoding, digest: &Integrity) -> String {
+    format!("encoded:{}:{digest}", enc.name())
+}
+
+/// Whether body of response with `headers` and `len` bytes should be compressed.
+pub(crate) fn is_compressible(headers: &HeaderMap, len: usize) -> bool {
+    if len < MIN_COMPRESS_SIZE || headers.contains_key(header::CONTENT_ENCODING) {
+        return false;
+    }
+    let mime = match headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(v) => v
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase(),
+        None => return false,
+    };
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/javascript"
+                | "application/x-javascript"
+                | "application/ecmascript"
+                | "application/json"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+        )
+}
+
+/// Entity tag of the `enc` encoded representation, derived from `etag` of identity.
+pub(crate) fn etag(etag: &HeaderValue, enc: Encoding) -> HeaderValue {
+    match etag.to_str().ok().and_then(|e| e.strip_suffix('"')) {
+        Some(e) => HeaderValue::from_str(&format!("{e}-{}\"", enc.name())).unwrap(),
+        None => etag.clone(),
+    }
+}
+
+/// Add `Accept-Encoding` to `Vary` of response `headers` if it is not there.
+pub(crate) fn add_vary(headers: &mut HeaderMap) {
+    let varied = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| {
+            let v = v.trim();
+            v == "*" || v.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !varied {
+        headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn negotiate(accept: &str) -> Option<&'static str> {
+        let headers = HeaderMap::from_iter([(header::ACCEPT_ENCODING, accept.parse().unwrap())]);
+        Encoding::negotiate(&headers).map(Encoding::name)
+    }
+
+    #[test]
+    fn negotiate_preference() {
+        assert_eq!(negotiate("gzip"), Some("gzip"));
+        assert_eq!(negotiate("gzip, deflate, br, zstd"), Some("br"));
+        assert_eq!(negotiate("gzip, zstd"), Some("zstd"));
+        assert_eq!(negotiate("br;q=0.5, gzip;q=0.8"), Some("gzip"));
+        assert_eq!(negotiate("GZIP"), Some("gzip"));
+        assert_eq!(negotiate("deflate, identity"), None);
+        assert_eq!(Encoding::negotiate(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn negotiate_refused() {
+        assert_eq!(negotiate("br;q=0, gzip"), Some("gzip"));
+        assert_eq!(negotiate("br;q=0, zstd;q=0, gzip;q=0"), None);
+        assert_eq!(negotiate("gzip;q=0.0"), None);
+    }
+
+    #[test]
+    fn negotiate_wildcard() {
+        assert_eq!(negotiate("*"), Some("br"));
+        assert_eq!(negotiate("br;q=0, *"), Some("zstd"));
+        assert_eq!(negotiate("gzip, *;q=0.5"), Some("gzip"));
+        assert_eq!(negotiate("gzip;q=0.5, *"), Some("br"));
+        assert_eq!(negotiate("br;q=0, zstd;q=0, *"), Some("gzip"));
+        assert_eq!(negotiate("*;q=0"), None);
+        assert_eq!(negotiate("gzip, *;q=0"), Some("gzip"));
+    }
+
+    #[test]
+    fn encoders_round_trip() {
+        let data = "cached body ".repeat(1000);
+        for enc in Encoding::SUPPORTED {
+            let mut w = enc.encoder(Vec::new()).unwrap();
+            w.write_all(data.as_bytes()).unwrap();
+            let encoded = w.finish().unwrap();
+            assert!(encoded.len() < data.len(), "{}", enc.name());
+            let mut decoded = String::new();
+            match enc {
+                Encoding::Brotli => brotli::Decompressor::new(&encoded[..], 4096)
+                    .read_to_string(&mut decoded)
+                    .unwrap(),
+                Encoding::Zstd => zstd::Decoder::new(&encoded[..])
+                    .unwrap()
+                    .read_to_string(&mut decoded)
+                    .unwrap(),
+                Encoding::Gzip => flate2::read::GzDecoder::new(&encoded[..])
+                    .read_to_string(&mut decoded)
+                    .unwrap(),
+            };
+            assert_eq!(decoded, data, "{}", enc.name());
+        }
+    }
+}
@@ -0,0 +1,668 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderName, HeaderValue};
+use http_cache_semantics::CachePolicy;
+use ssri::Integrity;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::PollSender;
+
+use crate::{flight::FlightGuard, gc};
+
+/// Cached response.
+///
+/// The policy is stored in index metadata of the entry, and the body is its
+/// content, which is addressed by integrity so that identical bodies under
+/// different keys are stored once.
+#[derive(Clone)]
+pub(crate) struct CacheEntry {
+    pub(crate) policy: CachePolicy,
+    /// Integrity of the body.
+    pub(crate) integrity: Integrity,
+    /// Length of the body.
+    pub(crate) size: u64,
+}
+/// Version of the entry format, recorded in index metadata of entries.
+///
+/// 0. Policy, body and digest of the body encoded as a CBOR map in content,
+///    without version recorded.
+/// 1. Policy in index metadata and body as content. Entries stored before
+///    the version was recorded have no version but a policy.
+pub(crate) const ENTRY_VERSION: u32 = 1;
+
+/// Entry of version 0, the digest is not needed as content is verified by
+/// cacache.
+#[derive(serde::Deserialize)]
+struct EntryV0 {
+    policy: CachePolicy,
+    body: Bytes,
+}
+
+/// Entry that can't be read, which is treated as missing.
+#[derive(Debug)]
+pub(crate) enum InvalidEntry {
+    UnsupportedVersion(serde_json::Value),
+    Policy(serde_json::Error),
+    Body(cacache::Error),
+    MissingBody,
+    DecodeV0(ciborium::de::Error<io::Error>),
+    /// Record of [`write_vary_index`], which is not an entry.
+    VaryIndex,
+}
+impl std::fmt::Display for InvalidEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedVersion(v) => write!(f, "unsupported entry version {v}"),
+            Self::Policy(e) => write!(f, "failed to decode policy: {e}"),
+            Self::Body(e) => write!(f, "failed to read body: {e}"),
+            Self::MissingBody => f.write_str("body is missing"),
+            Self::DecodeV0(e) => write!(f, "failed to decode entry of version 0: {e}"),
+            Self::VaryIndex => f.write_str("record is a vary index"),
+        }
+    }
+}
+
+/// Entry as it is stored.
+pub(crate) enum Stored {
+    Current(CacheEntry),
+    /// Entry of version 0, which is rewritten by [`upgrade`].
+    V0 {
+        policy: CachePolicy,
+        body: Bytes,
+    },
+}
+impl Stored {
+    pub(crate) fn policy(&self) -> &CachePolicy {
+        match self {
+            Self::Current(e) => &e.policy,
+            Self::V0 { policy, .. } => policy,
+        }
+    }
+}
+
+/// Decode entry of index record `md` in any supported version.
+pub(crate) fn decode(root: &Path, md: &cacache::Metadata) -> Result<Stored, InvalidEntry> {
+    // vary index has neither version nor policy, like entries of version 0
+    if md.metadata.get("vary").is_some() {
+        return Err(InvalidEntry::VaryIndex);
+    }
+    let version = match md.metadata.get("version") {
+        Some(v) => match v.as_u64() {
+            Some(v) if v <= ENTRY_VERSION as u64 => v as u32,
+            _ => return Err(InvalidEntry::UnsupportedVersion(v.clone())),
+        },
+        None if md.metadata.get("policy").is_some() => 1,
+        None => 0,
+    };
+    match version {
+        0 => {
+            let reader = cacache::SyncReader::open_hash(root, md.integrity.clone())
+                .map_err(InvalidEntry::Body)?;
+            let mut reader = io::BufReader::new(reader);
+            let entry: EntryV0 =
+                ciborium::from_reader(&mut reader).map_err(InvalidEntry::DecodeV0)?;
+            // integrity is computed over the whole content
+            io::copy(&mut reader, &mut io::sink()).map_err(|e| {
+                InvalidEntry::Body(cacache::Error::IoError(e, "failed to read entry".into()))
+            })?;
+            reader.into_inner().check().map_err(InvalidEntry::Body)?;
+            Ok(Stored::V0 {
+                policy: entry.policy,
+                body: entry.body,
+            })
+        }
+        _ => Ok(Stored::Current(CacheEntry {
+            policy: serde_json::from_value(md.metadata["policy"].clone())
+                .map_err(InvalidEntry::Policy)?,
+            size: gc::content_size(root, &md.integrity).ok_or(InvalidEntry::MissingBody)?,
+            integrity: md.integrity.clone(),
+        })),
+    }
+}
+
+/// Rewrite entry of index record `md` in the current version.
+pub(crate) fn upgrade(
+    root: &Path,
+    md: &cacache::Metadata,
+    policy: CachePolicy,
+    body: &Bytes,
+) -> Result<CacheEntry, cacache::Error> {
+    let mut metadata = EntryMetadata::read(md);
+    metadata.policy = Some(policy.clone());
+    Ok(CacheEntry {
+        integrity: write_entry(root, &md.key, &metadata, body)?,
+        size: body.len() as u64,
+        policy,
+    })
+}
+
+/// Outcome of [`load`].
+pub(crate) enum Loaded {
+    Current(CacheEntry),
+    Upgraded(CacheEntry),
+    Evicted,
+    /// Record is a vary index, which is kept.
+    VaryIndex,
+}
+
+/// Entry of index record `md`, which is upgraded if it is of an older
+/// version, or evicted if it can't be read.
+pub(crate) fn load(root: &Path, md: &cacache::Metadata) -> Result<Loaded, cacache::Error> {
+    match decode(root, md) {
+        Ok(Stored::Current(entry)) => Ok(Loaded::Current(entry)),
+        Ok(Stored::V0 { policy, body }) => {
+            tracing::info!(key = md.key, "upgrading entry of version 0");
+            upgrade(root, md, policy, &body).map(Loaded::Upgraded)
+        }
+        Err(InvalidEntry::VaryIndex) => Ok(Loaded::VaryIndex),
+        Err(e) => {
+            tracing::warn!(key = md.key, "evicting invalid cache entry: {e}");
+            cacache::remove_sync(root, &md.key)?;
+            Ok(Loaded::Evicted)
+        }
+    }
+}
+
+/// Read the whole body with `integrity`, which is verified.
+pub(crate) fn read_body(root: &Path, integrity: &Integrity) -> Result<Bytes, cacache::Error> {
+    cacache::read_hash_sync(root, integrity).map(Bytes::from)
+}
+
+/// Write `body` under `key` with `metadata`, which should contain its policy.
+pub(crate) fn write_entry(
+    root: &Path,
+    key: &str,
+    metadata: &EntryMetadata,
+    body: &[u8],
+) -> Result<Integrity, cacache::Error> {
+    let mut writer = cacache::WriteOpts::new()
+        .metadata(metadata.to_json())
+        .open_sync(root, key)?;
+    writer
+        .write_all(body)
+        .map_err(|e| cacache::Error::IoError(e, format!("failed to write entry {key}")))?;
+    writer.commit()
+}
+
+/// Replace metadata of entry under `key`, keeping its body.
+pub(crate) fn update_metadata(
+    root: &Path,
+    key: &str,
+    metadata: &EntryMetadata,
+    entry: &CacheEntry,
+) -> Result<(), cacache::Error> {
+    cacache::index::insert(
+        root,
+        key,
+        cacache::WriteOpts::new()
+            .integrity(entry.integrity.clone())
+            .size(entry.size as usize)
+            .metadata(metadata.to_json()),
+    )?;
+    Ok(())
+}
+
+/// Strong entity tag of a body with `digest`.
+pub(crate) fn etag(digest: &Integrity) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{}\"", digest.to_hex().1)).unwrap()
+}
+
+/// Header names in `Vary` of a response.
+///
+/// Returns `None` if the response does not vary on request headers, or varies
+/// on `*` so that it can't be matched. `Accept-Encoding` is ignored because
+/// bodies are stored decoded.
+pub(crate) fn vary_headers(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    for name in headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+    {
+        if name == "*" {
+            return None;
+        }
+        match HeaderName::try_from(name) {
+            Ok(h) if h != header::ACCEPT_ENCODING => names.push(h),
+            Ok(_) => (),
+            Err(_) => return None,
+        }
+    }
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+    if names.is_empty() {
+        None
+    } else {
+        Some(names)
+    }
+}
+
+/// Key of the variant selected by `headers`, stored under primary key `primary`.
+pub(crate) fn variant_key(primary: &str, vary: &[HeaderName], headers: &HeaderMap) -> String {
+    let mut key = primary.to_string();
+    for name in vary {
+        key.push('\n');
+        key.push_str(name.as_str());
+        let mut values = headers
+            .get_all(name)
+            .iter()
+            .flat_map(|v| v.as_bytes().split(|b| *b == b','))
+            .map(|v| String::from_utf8_lossy(v.trim_ascii()))
+            .peekable();
+        if values.peek().is_some() {
+            key.push(':');
+            for (i, v) in values.enumerate() {
+                key.push_str(if i == 0 { " " } else { "," });
+                key.push_str(&v);
+            }
+        }
+    }
+    key
+}
+
+/// Primary key of `key` and request headers selecting the variant, reversing
+/// [`variant_key`].
+pub(crate) fn split_variant_key(key: &str) -> (&str, HeaderMap) {
+    let mut lines = key.split('\n');
+    let primary = lines.next().unwrap_or_default();
+    let mut headers = HeaderMap::new();
+    for (name, value) in lines.filter_map(|l| l.split_once(": ")) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::from_str(value)) {
+            headers.append(name, value);
+        }
+    }
+    (primary, headers)
+}
+
+/// Record that responses under `primary` vary on `vary` headers.
+///
+/// The record is stored in index metadata with an empty body, variants are
+/// stored under [`variant_key`].
+pub(crate) fn write_vary_index(
+    root: &Path,
+    primary: &str,
+    vary: &[HeaderName],
+) -> Result<(), cacache::Error> {
+    cacache::WriteOpts::new()
+        .metadata(serde_json::json!({
+            "vary": vary.iter().map(HeaderName::as_str).collect::<Vec<_>>()
+        }))
+        .open_sync(root, primary)?
+        .commit()?;
+    Ok(())
+}
+
+/// Vary headers recorded by [`write_vary_index`], `None` if `md` is a normal entry.
+pub(crate) fn read_vary_index(md: &cacache::Metadata) -> Option<Vec<HeaderName>> {
+    md.metadata
+        .get("vary")?
+        .as_array()?
+        .iter()
+        .map(|v| v.as_str().and_then(|v| HeaderName::try_from(v).ok()))
+        .collect()
+}
+
+/// Find entry for request, returns the key of the entry and the entry if it
+/// is stored.
+///
+/// If responses of the path vary on request headers, the key of variant
+/// selected by `headers` is returned.
+pub(crate) fn find_entry(
+    root: &Path,
+    primary: &str,
+    headers: &HeaderMap,
+) -> Result<(String, Option<CacheEntry>), cacache::Error> {
+    let md = match cacache::index::find(root, primary)? {
+        Some(md) => md,
+        None => return Ok((primary.to_string(), None)),
+    };
+    let (key, md) = match read_vary_index(&md) {
+        Some(vary) => {
+            let key = variant_key(primary, &vary, headers);
+            let md = cacache::index::find(root, &key)?;
+            (key, md)
+        }
+        None => (primary.to_string(), Some(md)),
+    };
+    let entry = match md {
+        Some(md) => match load(root, &md)? {
+            Loaded::Current(e) | Loaded::Upgraded(e) => Some(e),
+            // variant key never holds a vary index
+            Loaded::Evicted | Loaded::VaryIndex => None,
+        },
+        None => None,
+    };
+    Ok((key, entry))
+}
+
+/// Index metadata of a stored response.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct EntryMetadata {
+    /// Request uri, which may differ from the normalized key of the entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) uri: Option<String>,
+    /// Upstream mirror the response was received from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mirror: Option<String>,
+    /// Cache policy of the response, missing in entries of version 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) policy: Option<CachePolicy>,
+}
+/// Metadata with version of the entry format.
+#[derive(serde::Serialize)]
+struct Versioned<'a> {
+    version: u32,
+    #[serde(flatten)]
+    metadata: &'a EntryMetadata,
+}
+impl EntryMetadata {
+    /// Metadata recorded in `md`, fields are missing in entries stored before
+    /// they were recorded, or if they can't be decoded.
+    pub(crate) fn read(md: &cacache::Metadata) -> Self {
+        fn field<T: serde::de::DeserializeOwned>(md: &cacache::Metadata, name: &str) -> Option<T> {
+            serde_json::from_value(md.metadata.get(name)?.clone()).ok()
+        }
+        Self {
+            uri: field(md, "uri"),
+            mirror: field(md, "mirror"),
+            policy: field(md, "policy"),
+        }
+    }
+    /// JSON of metadata of an entry in the current version.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(Versioned {
+            version: ENTRY_VERSION,
+            metadata: self,
+        })
+        .unwrap()
+    }
+}
+
+/// Writes the body of a cache entry while it is still being received.
+///
+/// Nothing is visible in the cache until [`EntryWriter::commit`] succeeds,
+/// dropping the writer discards the partially written content.
+struct EntryWriter {
+    writer: cacache::SyncWriter,
+    expected_len: Option<u64>,
+    written: u64,
+}
+impl EntryWriter {
+    fn create(
+        root: &Path,
+        key: &str,
+        metadata: &EntryMetadata,
+        expected_len: Option<u64>,
+    ) -> Result<Self, cacache::Error> {
+        let writer = cacache::WriteOpts::new()
+            .metadata(metadata.to_json())
+            .open_sync(root, key)?;
+        Ok(Self {
+            writer,
+            expected_len,
+            written: 0,
+        })
+    }
+    fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.writer.write_all(chunk)?;
+        self.written += chunk.len() as u64;
+        Ok(())
+    }
+    /// Commit the entry to the cache index.
+    ///
+    /// Fails without committing if the body length does not match the
+    /// `Content-Length` of the upstream response.
+    fn commit(self) -> Result<cacache::Integrity, cacache::Error> {
+        if let Some(expected) = self.expected_len {
+            if expected != self.written {
+                return Err(cacache::Error::SizeMismatch(
+                    expected as usize,
+                    self.written as usize,
+                ));
+            }
+        }
+        self.writer.commit()
+    }
+}
+
+/// [`EntryWriter`] running in the blocking thread pool, so that writing the
+/// body to disk never blocks the runtime.
+///
+/// At most [`BackgroundWriter::QUEUE`] chunks are queued, if the disk is
+/// slower than upstream, [`BackgroundWriter::poll_reserve`] is pending until
+/// the writer catches up. The entry is committed after
+/// [`BackgroundWriter::commit`], and discarded if the handle is dropped
+/// before. `flight` is released once the writer is finished.
+pub(crate) struct BackgroundWriter {
+    key: String,
+    tx: PollSender<Bytes>,
+    commit: oneshot::Sender<()>,
+    committed: oneshot::Receiver<(Integrity, u64)>,
+    expected_len: Option<u64>,
+    received: u64,
+}
+impl BackgroundWriter {
+    const QUEUE: usize = 16;
+
+    pub(crate) fn spawn(
+        root: Arc<Path>,
+        key: String,
+        metadata: EntryMetadata,
+        expected_len: Option<u64>,
+        flight: FlightGuard,
+    ) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Bytes>(Self::QUEUE);
+        let (commit, mut commit_rx) = oneshot::channel();
+        let (committed_tx, committed) = oneshot::channel();
+        let span = tracing::Span::current();
+        let task_key = key.clone();
+        tokio::task::spawn_blocking(move || {
+            let _enter = span.enter();
+            let _flight = flight;
+            let key = task_key;
+            let mut writer = match EntryWriter::create(&root, &key, &metadata, expected_len) {
+                Ok(w) => w,
+                Err(e) => {
+                    tracing::error!(key, "failed to create cache entry: {e}");
+                    return;
+                }
+            };
+            while let Some(chunk) = rx.blocking_recv() {
+                if let Err(e) = writer.write(&chunk) {
+                    tracing::error!(key, "failed to write cache entry: {e}");
+                    return;
+                }
+            }
+            // queued chunks are received before the channel is closed
+            if commit_rx.try_recv().is_err() {
+                return;
+            }
+            let size = writer.written;
+            match writer.commit() {
+                Ok(integrity) => {
+                    tracing::info!(key, %integrity, "cache entry committed");
+                    let _ = committed_tx.send((integrity, size));
+                }
+                Err(e) => tracing::error!(key, "failed to commit cache entry: {e}"),
+            }
+        });
+        Self {
+            key,
+            tx: PollSender::new(tx),
+            commit,
+            committed,
+            expected_len,
+            received: 0,
+        }
+    }
+    pub(crate) fn key(&self) -> &str {
+        &self.key
+    }
+    /// Whether all bytes of `Content-Length` of the upstream response are received.
+    pub(crate) fn is_complete(&self) -> bool {
+        self.expected_len == Some(self.received)
+    }
+    /// Wait until a chunk can be queued, returns `false` if the writer has failed.
+    pub(crate) fn poll_reserve(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        self.tx.poll_reserve(cx).map(|r| r.is_ok())
+    }
+    /// Queue `chunk` to be written after [`Self::poll_reserve`] is ready,
+    /// returns `false` if the writer has failed.
+    pub(crate) fn write(&mut self, chunk: Bytes) -> bool {
+        self.received += chunk.len() as u64;
+        self.tx.send_item(chunk).is_ok()
+    }
+    /// Commit the entry once queued chunks are written, the returned channel
+    /// receives integrity and size of the body if it is committed.
+    pub(crate) fn commit(self) -> oneshot::Receiver<(Integrity, u64)> {
+        let _ = self.commit.send(());
+        self.committed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"console.log(1)";
+
+    fn policy() -> CachePolicy {
+        let req = http::Request::get("https://example.com/lib.js")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        let res = http::Response::builder()
+            .header(header::CACHE_CONTROL, "max-age=60")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        CachePolicy::new(&req, &res)
+    }
+
+    fn index(root: &Path) -> cacache::Metadata {
+        cacache::index::find(root, "k").unwrap().unwrap()
+    }
+
+    /// Write an entry of version 0, with everything in content.
+    fn write_v0(root: &Path) {
+        #[derive(serde::Serialize)]
+        struct EntryV0 {
+            policy: CachePolicy,
+            body: Bytes,
+            digest: Bytes,
+        }
+        let mut content = Vec::new();
+        let entry = EntryV0 {
+            policy: policy(),
+            body: Bytes::from_static(BODY),
+            digest: Bytes::from_static(b"digest"),
+        };
+        ciborium::into_writer(&entry, &mut content).unwrap();
+        cacache::write_sync(root, "k", content).unwrap();
+    }
+
+    fn write_metadata(root: &Path, metadata: serde_json::Value) {
+        cacache::WriteOpts::new()
+            .metadata(metadata)
+            .open_sync(root, "k")
+            .and_then(|mut w| {
+                w.write_all(BODY).unwrap();
+                w.commit()
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn decode_v0() {
+        let dir = tempfile::tempdir().unwrap();
+        write_v0(dir.path());
+        match decode(dir.path(), &index(dir.path())).unwrap() {
+            Stored::V0 { body, .. } => assert_eq!(body, BODY),
+            Stored::Current(_) => panic!("entry of version 0 decoded as current"),
+        }
+
+        let md = index(dir.path());
+        let entry = match load(dir.path(), &md).unwrap() {
+            Loaded::Upgraded(e) => e,
+            _ => panic!("entry of version 0 is not upgraded"),
+        };
+        assert_eq!(entry.size, BODY.len() as u64);
+        assert_eq!(read_body(dir.path(), &entry.integrity).unwrap(), BODY);
+        let md = index(dir.path());
+        assert_eq!(md.metadata["version"], ENTRY_VERSION);
+        assert!(matches!(load(dir.path(), &md).unwrap(), Loaded::Current(_)));
+    }
+
+    #[test]
+    fn decode_current() {
+        let dir = tempfile::tempdir().unwrap();
+        let metadata = EntryMetadata {
+            policy: Some(policy()),
+            ..Default::default()
+        };
+        let integrity = write_entry(dir.path(), "k", &metadata, BODY).unwrap();
+        match decode(dir.path(), &index(dir.path())).unwrap() {
+            Stored::Current(e) => {
+                assert_eq!(e.integrity, integrity);
+                assert_eq!(e.size, BODY.len() as u64);
+            }
+            Stored::V0 { .. } => panic!("current entry decoded as version 0"),
+        }
+    }
+
+    #[test]
+    fn decode_unversioned_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = serde_json::to_value(policy()).unwrap();
+        write_metadata(dir.path(), serde_json::json!({ "policy": policy }));
+        assert!(matches!(
+            decode(dir.path(), &index(dir.path())),
+            Ok(Stored::Current(_))
+        ));
+    }
+
+    #[test]
+    fn vary_index_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        write_vary_index(dir.path(), "k", &[header::ACCEPT_LANGUAGE]).unwrap();
+        assert!(matches!(
+            decode(dir.path(), &index(dir.path())),
+            Err(InvalidEntry::VaryIndex)
+        ));
+        assert!(matches!(
+            load(dir.path(), &index(dir.path())).unwrap(),
+            Loaded::VaryIndex
+        ));
+        assert!(read_vary_index(&index(dir.path())).is_some());
+    }
+
+    #[test]
+    fn decode_future_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = serde_json::to_value(policy()).unwrap();
+        write_metadata(
+            dir.path(),
+            serde_json::json!({ "version": ENTRY_VERSION + 1, "policy": policy }),
+        );
+        assert!(matches!(
+            decode(dir.path(), &index(dir.path())),
+            Err(InvalidEntry::UnsupportedVersion(v)) if v == ENTRY_VERSION + 1
+        ));
+
+        assert!(matches!(
+            load(dir.path(), &index(dir.path())).unwrap(),
+            Loaded::Evicted
+        ));
+        assert!(cacache::index::find(dir.path(), "k").unwrap().is_none());
+    }
+}
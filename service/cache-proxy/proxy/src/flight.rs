@@ -0,0 +1,60 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::watch;
+
+/// Requests to upstream that are currently in progress, keyed by cache key.
+///
+/// Only one request per key is sent to upstream at a time, other requests
+/// of the same key wait for it and then read the result from cache.
+#[derive(Clone, Default)]
+pub(crate) struct InFlight(Arc<Mutex<HashMap<String, watch::Receiver<()>>>>);
+
+pub(crate) enum Flight {
+    Leader(FlightGuard),
+    Follower(Follower),
+}
+
+impl InFlight {
+    pub(crate) fn join(&self, key: &str) -> Flight {
+        let mut map = self.0.lock().unwrap();
+        match map.get(key) {
+            Some(rx) => Flight::Follower(Follower(rx.clone())),
+            None => {
+                let (tx, rx) = watch::channel(());
+                map.insert(key.to_string(), rx);
+                Flight::Leader(FlightGuard {
+                    in_flight: self.clone(),
+                    key: key.to_string(),
+                    _done: tx,
+                })
+            }
+        }
+    }
+}
+
+/// Held by the request that updates cache entry of the key.
+///
+/// Waiting requests are woken up when it is dropped, whether the entry
+/// is written or not.
+pub(crate) struct FlightGuard {
+    in_flight: InFlight,
+    key: String,
+    _done: watch::Sender<()>,
+}
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.0.lock().unwrap().remove(&self.key);
+    }
+}
+
+pub(crate) struct Follower(watch::Receiver<()>);
+impl Follower {
+    pub(crate) async fn wait(mut self) {
+        tracing::info!("waiting for in-flight request of the same key");
+        // nothing is sent on the channel, so this only returns when the guard is dropped
+        while self.0.changed().await.is_ok() {}
+    }
+}
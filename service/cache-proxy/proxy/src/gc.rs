@@ -0,0 +1,516 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+
+/// Content written this recently is never removed, because its index
+/// entry may not be inserted yet.
+const CONTENT_GRACE: Duration = Duration::from_secs(60);
+
+/// Order of evicting entries when the cache exceeds its size limit.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Eviction {
+    /// Least recently used first.
+    #[default]
+    Lru,
+    /// Least frequently used first.
+    Lfu,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+struct Access {
+    /// Seconds since unix epoch.
+    last: u64,
+    hits: u64,
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Last access time and access count of cache entries, recorded when
+/// entries are read.
+///
+/// It is saved in cache root by the garbage collector, so that it survives restarts.
+#[derive(Clone, Default)]
+pub struct AccessLog(Arc<Mutex<HashMap<String, Access>>>);
+impl AccessLog {
+    const FILE: &'static str = "access-log";
+
+    /// Load access log saved in cache `root`, empty if there is none.
+    pub fn load(root: &Path) -> Self {
+        let map = match fs::File::open(root.join(Self::FILE)) {
+            Ok(f) => match ciborium::from_reader(io::BufReader::new(f)) {
+                Ok(m) => m,
+                Err(e) => {
+                    tracing::warn!("failed to decode access log, discarded: {e}");
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                tracing::warn!("failed to open access log: {e}");
+                HashMap::new()
+            }
+        };
+        Self(Arc::new(Mutex::new(map)))
+    }
+    fn save(&self, root: &Path) -> io::Result<()> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(&*self.0.lock().unwrap(), &mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let tmp = root.join(format!("{}.tmp", Self::FILE));
+        fs::write(&tmp, buf)?;
+        fs::rename(tmp, root.join(Self::FILE))
+    }
+    pub(crate) fn record(&self, key: &str) {
+        let now = unix_secs(SystemTime::now());
+        let mut map = self.0.lock().unwrap();
+        match map.get_mut(key) {
+            Some(a) => {
+                a.last = now;
+                a.hits += 1;
+            }
+            None => {
+                map.insert(key.to_string(), Access { last: now, hits: 1 });
+            }
+        }
+    }
+}
+
+/// Limits enforced by the garbage collector, nothing is evicted if unset.
+#[derive(Debug, Clone, Default)]
+pub struct GcConfig {
+    /// Maximum total size of cached content in bytes.
+    pub max_size: Option<u64>,
+    /// Entries not accessed for this long are removed.
+    pub max_age: Option<Duration>,
+    pub eviction: Eviction,
+}
+
+#[derive(Debug, Default)]
+pub struct GcStats {
+    pub entries: usize,
+    pub blobs: usize,
+    pub reclaimed: u64,
+}
+
+struct Entry {
+    key: String,
+    /// Integrity and time of the entry when the index is listed.
+    integrity: cacache::Integrity,
+    time: u128,
+    /// Name of content in [`list_content`].
+    content: String,
+    size: u64,
+    access: Access,
+}
+
+struct Content {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+fn io_error(root: &Path) -> impl FnOnce(io::Error) -> cacache::Error + '_ {
+    move |e| {
+        cacache::Error::IoError(
+            e,
+            format!("failed to collect garbage in {}", root.display()),
+        )
+    }
+}
+
+/// Name of content with `integrity` in [`list_content`].
+fn content_name(integrity: &cacache::Integrity) -> String {
+    let (algo, hex) = integrity.to_hex();
+    format!("{algo}/{hex}")
+}
+
+/// Path of content with `integrity` in cache `root`.
+pub(crate) fn content_path(root: &Path, integrity: &cacache::Integrity) -> PathBuf {
+    let (algo, hex) = integrity.to_hex();
+    root.join("content-v2")
+        .join(algo.to_string())
+        .join(&hex[0..2])
+        .join(&hex[2..4])
+        .join(&hex[4..])
+}
+
+/// Size of content with `integrity` in cache `root`.
+pub(crate) fn content_size(root: &Path, integrity: &cacache::Integrity) -> Option<u64> {
+    fs::metadata(content_path(root, integrity))
+        .ok()
+        .map(|md| md.len())
+}
+
+/// Index entries in cache `root`, empty if nothing has been cached yet.
+pub(crate) fn list_index(root: &Path) -> Result<Vec<cacache::Metadata>, cacache::Error> {
+    if !root.join("index-v5").exists() {
+        return Ok(Vec::new());
+    }
+    cacache::list_sync(root).collect()
+}
+
+/// Content files in cache `root`, keyed by `{algorithm}/{hex digest}`.
+///
+/// Content is stored at `content-v2/{algorithm}/{hex[..2]}/{hex[2..4]}/{hex[4..]}`.
+fn list_content(root: &Path) -> io::Result<HashMap<String, Content>> {
+    fn walk(dir: &Path, name: &str, out: &mut HashMap<String, Content>) -> io::Result<()> {
+        for e in fs::read_dir(dir)? {
+            let e = e?;
+            let name = match name {
+                "" => format!("{}/", e.file_name().to_string_lossy()),
+                _ => format!("{name}{}", e.file_name().to_string_lossy()),
+            };
+            if e.file_type()?.is_dir() {
+                walk(&e.path(), &name, out)?;
+            } else {
+                let md = e.metadata()?;
+                out.insert(
+                    name,
+                    Content {
+                        path: e.path(),
+                        size: md.len(),
+                        modified: md.modified()?,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+    let mut content = HashMap::new();
+    match walk(&root.join("content-v2"), "", &mut content) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(content),
+        r => r.map(|_| content),
+    }
+}
+
+/// Remove entries exceeding limits of `config`, then remove content that
+/// is no longer referenced by any entry.
+pub fn collect(
+    root: &Path,
+    access: &AccessLog,
+    config: &GcConfig,
+) -> Result<GcStats, cacache::Error> {
+    let start = SystemTime::now();
+    let now = unix_secs(start);
+    let mut content = list_content(root).map_err(io_error(root))?;
+    let log = access.0.lock().unwrap().clone();
+    // size recorded in index is not reliable, use size of content instead
+    let mut entries = list_index(root)?
+        .into_iter()
+        .map(|md| {
+            let name = content_name(&md.integrity);
+            let written = (md.time / 1000) as u64;
+            let access = match log.get(&md.key) {
+                Some(a) => Access {
+                    last: a.last.max(written),
+                    hits: a.hits,
+                },
+                None => Access {
+                    last: written,
+                    hits: 0,
+                },
+            };
+            Entry {
+                key: md.key,
+                integrity: md.integrity,
+                time: md.time,
+                size: content.get(&name).map_or(0, |c| c.size),
+                content: name,
+                access,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut removed = Vec::new();
+    if let Some(max_age) = config.max_age {
+        let (expired, kept) = entries
+            .into_iter()
+            .partition(|e: &Entry| now.saturating_sub(e.access.last) > max_age.as_secs());
+        removed = expired;
+        entries = kept;
+    }
+    if let Some(max_size) = config.max_size {
+        match config.eviction {
+            Eviction::Lru => entries.sort_by_key(|e| std::cmp::Reverse(e.access.last)),
+            Eviction::Lfu => {
+                entries.sort_by_key(|e| std::cmp::Reverse((e.access.hits, e.access.last)))
+            }
+        }
+        let mut total = 0;
+        let mut full = false;
+        let mut kept = Vec::new();
+        // identical bodies share content, which is only counted once
+        let mut counted = HashSet::new();
+        for e in entries {
+            let size = if counted.contains(&e.content) {
+                0
+            } else {
+                e.size
+            };
+            full = full || total + size > max_size;
+            if full && e.size > 0 {
+                removed.push(e);
+            } else {
+                total += size;
+                counted.insert(e.content.clone());
+                kept.push(e);
+            }
+        }
+        entries = kept;
+    }
+
+    let mut stats = GcStats::default();
+    for mut e in removed {
+        // the entry may be written again since the index is listed
+        match cacache::metadata_sync(root, &e.key)? {
+            Some(md) if md.integrity == e.integrity && md.time == e.time => {}
+            Some(md) => {
+                tracing::debug!(key = e.key, "cache entry is updated, not evicted");
+                e.content = content_name(&md.integrity);
+                entries.push(e);
+                continue;
+            }
+            None => continue,
+        }
+        tracing::debug!(key = e.key, size = e.size, "evicting cache entry");
+        cacache::remove_sync(root, &e.key)?;
+        stats.entries += 1;
+    }
+    // forget entries that are removed, but keep those recorded during collection
+    let live_keys = entries
+        .iter()
+        .map(|e| e.key.as_str())
+        .collect::<HashSet<_>>();
+    access
+        .0
+        .lock()
+        .unwrap()
+        .retain(|k, _| live_keys.contains(k.as_str()) || !log.contains_key(k));
+
+    for e in &entries {
+        content.remove(&e.content);
+    }
+    for (name, c) in content {
+        if c.modified >= start - CONTENT_GRACE {
+            continue;
+        }
+        tracing::debug!(
+            content = name,
+            size = c.size,
+            "removing unreferenced content"
+        );
+        fs::remove_file(&c.path).map_err(io_error(root))?;
+        stats.blobs += 1;
+        stats.reclaimed += c.size;
+    }
+
+    if let Err(e) = access.save(root) {
+        tracing::error!("failed to save access log: {e}");
+    }
+    Ok(stats)
+}
+
+/// Compact all index buckets of cache `root`, so that only the last line of
+/// each key is left, returns the number of bytes reclaimed.
+///
+/// The proxy must not be serving `root` at the same time, cacache appends
+/// lines to buckets without locking, which could be lost while a bucket is
+/// rewritten.
+pub(crate) fn compact_index(root: &Path) -> Result<u64, cacache::Error> {
+    fn walk(dir: &Path, reclaimed: &mut u64) -> io::Result<()> {
+        for e in fs::read_dir(dir)? {
+            let e = e?;
+            if e.file_type()?.is_dir() {
+                walk(&e.path(), reclaimed)?;
+            } else if e.path().extension().is_none() {
+                *reclaimed += compact_bucket(&e.path())?;
+            }
+        }
+        Ok(())
+    }
+    let index = root.join("index-v5");
+    let mut reclaimed = 0;
+    if index.exists() {
+        walk(&index, &mut reclaimed).map_err(io_error(root))?;
+    }
+    Ok(reclaimed)
+}
+
+/// Rewrite index `bucket` with only the last line of each key, dropping keys
+/// that are removed, returns the number of bytes reclaimed.
+fn compact_bucket(bucket: &Path) -> io::Result<u64> {
+    let data = match fs::read_to_string(bucket) {
+        Ok(d) => d,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    replace_bucket(bucket, &data, &compact_lines(&data))
+}
+
+/// Bucket `data` with only the last line of each key, without removed keys.
+///
+/// Each line is `\n{sha256 of json}\t{json}`, lines that don't match their
+/// hash are ignored by cacache and dropped.
+fn compact_lines(data: &str) -> String {
+    #[derive(serde::Deserialize)]
+    struct Line {
+        key: String,
+        integrity: Option<String>,
+    }
+    let mut last = HashMap::new();
+    for (i, line) in data.split('\n').enumerate() {
+        let Some((hash, json)) = line.split_once('\t') else {
+            continue;
+        };
+        if format!("{:x}", Sha256::digest(json)) != hash {
+            continue;
+        }
+        if let Ok(l) = serde_json::from_str::<Line>(json) {
+            last.insert(l.key, (i, l.integrity.is_some(), line));
+        }
+    }
+    // keep lines in the order they are written
+    let mut lines = last
+        .into_values()
+        .filter(|(_, live, _)| *live)
+        .map(|(i, _, line)| (i, line))
+        .collect::<Vec<_>>();
+    lines.sort_unstable();
+    lines.iter().map(|(_, l)| format!("\n{l}")).collect()
+}
+
+/// Replace `bucket` read as `data` with `compacted`, returns the number of
+/// bytes reclaimed.
+///
+/// The bucket is left as is if it is appended since it is read, which is
+/// only detected before it is replaced.
+fn replace_bucket(bucket: &Path, data: &str, compacted: &str) -> io::Result<u64> {
+    if compacted.len() >= data.len() {
+        return Ok(0);
+    }
+    let tmp = bucket.with_extension("tmp");
+    if !compacted.is_empty() {
+        fs::write(&tmp, compacted)?;
+    }
+    if fs::metadata(bucket)?.len() != data.len() as u64 {
+        let _ = fs::remove_file(&tmp);
+        return Ok(0);
+    }
+    if compacted.is_empty() {
+        fs::remove_file(bucket)?;
+    } else {
+        fs::rename(tmp, bucket)?;
+    }
+    Ok((data.len() - compacted.len()) as u64)
+}
+
+/// Collect garbage in cache `root` every `interval`.
+pub async fn run(root: PathBuf, access: AccessLog, config: GcConfig, interval: Duration) {
+    let mut timer = tokio::time::interval(interval);
+    loop {
+        timer.tick().await;
+        let (root, access, config) = (root.clone(), access.clone(), config.clone());
+        match tokio::task::spawn_blocking(move || collect(&root, &access, &config)).await {
+            Ok(Ok(stats)) => tracing::info!(
+                entries = stats.entries,
+                blobs = stats.blobs,
+                reclaimed = stats.reclaimed,
+                "garbage collection finished"
+            ),
+            Ok(Err(e)) => tracing::error!("garbage collection failed: {e}"),
+            Err(e) => tracing::error!("garbage collection panicked: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::Sha1;
+
+    use super::*;
+
+    /// Path of index bucket of `key` in cache `root`, as in `cacache::index`.
+    fn bucket_path(root: &Path, key: &str) -> PathBuf {
+        let hashed = format!("{:x}", Sha1::digest(key));
+        root.join("index-v5")
+            .join(&hashed[0..2])
+            .join(&hashed[2..4])
+            .join(&hashed[4..])
+    }
+
+    fn bucket_len(root: &Path, key: &str) -> u64 {
+        fs::metadata(bucket_path(root, key)).unwrap().len()
+    }
+
+    #[test]
+    fn compact_bucket_keeps_last_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        cacache::write_sync(root, "a", b"old").unwrap();
+        cacache::write_sync(root, "a", b"new").unwrap();
+        let size = bucket_len(root, "a");
+
+        let reclaimed = compact_bucket(&bucket_path(root, "a")).unwrap();
+        assert!(reclaimed > 0);
+        assert_eq!(bucket_len(root, "a"), size - reclaimed);
+        assert_eq!(cacache::read_sync(root, "a").unwrap(), b"new");
+        assert_eq!(compact_bucket(&bucket_path(root, "a")).unwrap(), 0);
+
+        cacache::remove_sync(root, "a").unwrap();
+        let size = bucket_len(root, "a");
+        assert_eq!(compact_bucket(&bucket_path(root, "a")).unwrap(), size);
+        assert!(!bucket_path(root, "a").exists());
+        assert!(cacache::metadata_sync(root, "a").unwrap().is_none());
+    }
+
+    #[test]
+    fn compact_bucket_keeps_appended_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        cacache::write_sync(root, "a", b"old").unwrap();
+        cacache::write_sync(root, "a", b"new").unwrap();
+        let bucket = bucket_path(root, "a");
+        let data = fs::read_to_string(&bucket).unwrap();
+        let compacted = compact_lines(&data);
+
+        // written while the bucket is compacted
+        cacache::write_sync(root, "a", b"newer").unwrap();
+        assert_eq!(replace_bucket(&bucket, &data, &compacted).unwrap(), 0);
+        assert_eq!(cacache::read_sync(root, "a").unwrap(), b"newer");
+        assert!(!bucket.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn compact_index_of_evicted_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        cacache::write_sync(root, "a", b"aaaa").unwrap();
+        cacache::write_sync(root, "b", b"bbbb").unwrap();
+        let access = AccessLog::default();
+        access.record("b");
+        let size = bucket_len(root, "a");
+
+        let config = GcConfig {
+            max_size: Some(4),
+            eviction: Eviction::Lfu,
+            ..Default::default()
+        };
+        let stats = collect(root, &access, &config).unwrap();
+        assert_eq!(stats.entries, 1);
+        // tombstone of the removed entry is reclaimed too
+        assert!(compact_index(root).unwrap() > size);
+        assert!(!bucket_path(root, "a").exists());
+        assert!(cacache::metadata_sync(root, "a").unwrap().is_none());
+        assert_eq!(cacache::read_sync(root, "b").unwrap(), b"bbbb");
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures_util::FutureExt;
+use http::{header, uri::Authority, Request, Response};
+use hyper::body::Incoming;
+use tower::ServiceExt;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{CacheLayer, CacheProxy, CachedResponse, ProxyError, UpstreamBody};
+
+/// Cache root of `authority` in cache directory `root`.
+pub fn host_root(root: &Path, authority: &Authority) -> PathBuf {
+    root.join(authority.as_str())
+}
+
+/// Authority of request, from `Host` header or uri of HTTP/2 requests.
+fn request_authority<B, E>(req: &Request<B>) -> Result<Authority, ProxyError<E>> {
+    match req.headers().get(header::HOST) {
+        Some(host) => Authority::try_from(host.as_bytes())
+            .map_err(|e| ProxyError::InvalidHost(host.clone(), e)),
+        None => req
+            .uri()
+            .authority()
+            .cloned()
+            .ok_or(ProxyError::MissingHost),
+    }
+}
+
+/// Proxy of several upstream hosts, which routes requests by their `Host`
+/// to the [`CacheProxy`] of the host.
+#[derive(Clone)]
+pub struct HostRouter<S> {
+    hosts: Arc<HashMap<Authority, CacheProxy<S>>>,
+}
+impl<S> HostRouter<S> {
+    pub fn new(proxies: impl IntoIterator<Item = CacheProxy<S>>) -> Self {
+        Self {
+            hosts: Arc::new(
+                proxies
+                    .into_iter()
+                    .map(|p| (p.authority.as_ref().clone(), p))
+                    .collect(),
+            ),
+        }
+    }
+    pub fn hosts(&self) -> impl Iterator<Item = &Authority> {
+        self.hosts.keys()
+    }
+    pub fn get(&self, authority: &Authority) -> Option<&CacheProxy<S>> {
+        self.hosts.get(authority)
+    }
+}
+
+/// Layer creating a [`HostRouter`] with a [`CacheProxy`] for each [`CacheLayer`],
+/// all sharing the same upstream service.
+pub struct HostLayer {
+    layers: Vec<CacheLayer>,
+}
+impl HostLayer {
+    pub fn new(layers: Vec<CacheLayer>) -> Self {
+        Self { layers }
+    }
+}
+impl<S: Clone> Layer<S> for HostLayer {
+    type Service = HostRouter<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        HostRouter::new(self.layers.iter().map(|l| l.layer(inner.clone())))
+    }
+}
+
+impl<S, E> Service<Request<Incoming>> for HostRouter<S>
+where
+    S: Clone + Send + 'static,
+    S: Service<Request<UpstreamBody>, Response = Response<Incoming>, Error = E>,
+    S::Future: Send,
+    E: Display + Send + 'static,
+{
+    type Response = CachedResponse;
+    type Error = ProxyError<E>;
+    type Future = <CacheProxy<S> as Service<Request<Incoming>>>::Future;
+    fn poll_ready(
+        &mut self,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        // readiness is awaited in `call` on the proxy of the request host
+        std::task::Poll::Ready(Ok(()))
+    }
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        let authority = match request_authority(&req) {
+            Ok(a) => a,
+            Err(e) => return crate::ProxyFuture::ready_err(e),
+        };
+        match self.hosts.get(&authority) {
+            Some(p) => crate::ProxyFuture::Boxed(p.clone().oneshot(req).boxed()),
+            None => crate::ProxyFuture::ready_err(ProxyError::UnknownHost(authority)),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Normalization of cache keys.
+//!
+//! Requests with the same normalized path and query share a cache entry,
+//! while upstream still receives the exact request uri.
+
+use std::borrow::Cow;
+
+use crate::rule::glob_match;
+
+/// How path and query of requests are normalized into cache keys.
+#[derive(Debug, Clone, Default)]
+pub struct KeyNormalization {
+    /// Sort query parameters.
+    pub sort_query: bool,
+    /// Drop query parameters with name matching any of the globs, e.g. `utm_*`.
+    pub drop_params: Vec<String>,
+    /// Collapse consecutive slashes in path.
+    pub collapse_slashes: bool,
+    /// Decode percent-encoded unreserved characters, and uppercase hex digits
+    /// of the other escapes.
+    pub decode_unreserved: bool,
+    /// Lowercase path.
+    pub lowercase_path: bool,
+}
+
+fn is_unreserved(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, b'-' | b'.' | b'_' | b'~')
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+fn decode_unreserved(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut ret = String::with_capacity(s.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(h), Some(l)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                let c = h << 4 | l;
+                if is_unreserved(c) {
+                    ret.push(c as char);
+                } else {
+                    ret.push('%');
+                    ret.push_str(&s[i + 1..i + 3].to_ascii_uppercase());
+                }
+                i += 3;
+                continue;
+            }
+        }
+        let len = s[i..].chars().next().map_or(1, char::len_utf8);
+        ret.push_str(&s[i..i + len]);
+        i += len;
+    }
+    ret
+}
+
+impl KeyNormalization {
+    fn is_identity(&self) -> bool {
+        !self.sort_query
+            && self.drop_params.is_empty()
+            && !self.collapse_slashes
+            && !self.decode_unreserved
+            && !self.lowercase_path
+    }
+    fn normalize_path(&self, path: &str) -> String {
+        let mut path = if self.decode_unreserved {
+            decode_unreserved(path)
+        } else {
+            path.to_string()
+        };
+        if self.collapse_slashes {
+            let mut prev = '\0';
+            path.retain(|c| {
+                let dup = c == '/' && prev == '/';
+                prev = c;
+                !dup
+            });
+        }
+        if self.lowercase_path {
+            path.make_ascii_lowercase();
+        }
+        path
+    }
+    fn normalize_query(&self, query: &str) -> String {
+        let mut params: Vec<Cow<str>> = query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .filter(|p| {
+                let name = p.split_once('=').map_or(*p, |(n, _)| n);
+                !self
+                    .drop_params
+                    .iter()
+                    .any(|d| glob_match(d.as_bytes(), name.as_bytes()))
+            })
+            .map(|p| match self.decode_unreserved {
+                true => Cow::Owned(decode_unreserved(p)),
+                false => Cow::Borrowed(p),
+            })
+            .collect();
+        if self.sort_query {
+            params.sort();
+        }
+        params.join("&")
+    }
+    /// Cache key of request with `path_and_query`.
+    pub fn normalize<'a>(&self, path_and_query: &'a str) -> Cow<'a, str> {
+        if self.is_identity() {
+            return Cow::Borrowed(path_and_query);
+        }
+        let (path, query) = match path_and_query.split_once('?') {
+            Some((p, q)) => (p, Some(q)),
+            None => (path_and_query, None),
+        };
+        let mut key = self.normalize_path(path);
+        if let Some(query) = query {
+            let query = self.normalize_query(query);
+            if !query.is_empty() {
+                key.push('?');
+                key.push_str(&query);
+            }
+        }
+        Cow::Owned(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity() {
+        let key = KeyNormalization::default();
+        assert!(matches!(
+            key.normalize("/a//B?z=1&a=2"),
+            Cow::Borrowed("/a//B?z=1&a=2")
+        ));
+    }
+
+    #[test]
+    fn sort_query() {
+        let key = KeyNormalization {
+            sort_query: true,
+            ..Default::default()
+        };
+        assert_eq!(key.normalize("/a?z=1&a=2&m"), "/a?a=2&m&z=1");
+        assert_eq!(key.normalize("/a?b=1&&a=2&"), "/a?a=2&b=1");
+        assert_eq!(key.normalize("/a?"), "/a");
+        assert_eq!(key.normalize("/a"), "/a");
+    }
+
+    #[test]
+    fn drop_params() {
+        let key = KeyNormalization {
+            drop_params: vec!["utm_*".to_string(), "fbclid".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(key.normalize("/a?utm_source=x&v=1&fbclid=y"), "/a?v=1");
+        assert_eq!(key.normalize("/a?utm_source&fbclid=y"), "/a");
+        assert_eq!(
+            key.normalize("/a?v=utm_source&fbclid2=y"),
+            "/a?v=utm_source&fbclid2=y"
+        );
+    }
+
+    #[test]
+    fn collapse_slashes() {
+        let key = KeyNormalization {
+            collapse_slashes: true,
+            ..Default::default()
+        };
+        assert_eq!(key.normalize("//a///b/?p=//x"), "/a/b/?p=//x");
+    }
+
+    #[test]
+    fn decode_unreserved() {
+        let key = KeyNormalization {
+            decode_unreserved: true,
+            ..Default::default()
+        };
+        assert_eq!(key.normalize("/%61%2Db%7e"), "/a-b~");
+        assert_eq!(key.normalize("/a%2fb%3F"), "/a%2Fb%3F");
+        assert_eq!(key.normalize("/%zz%4"), "/%zz%4");
+        assert_eq!(key.normalize("/é%41?q=%41%26"), "/éA?q=A%26");
+    }
+
+    #[test]
+    fn lowercase_path() {
+        let key = KeyNormalization {
+            lowercase_path: true,
+            ..Default::default()
+        };
+        assert_eq!(key.normalize("/A/B?Q=X"), "/a/b?Q=X");
+    }
+
+    #[test]
+    fn normalized_key_is_stable() {
+        let key = KeyNormalization {
+            sort_query: true,
+            drop_params: vec!["utm_*".to_string()],
+            collapse_slashes: true,
+            decode_unreserved: true,
+            lowercase_path: true,
+        };
+        let normalized = key.normalize("//A%2f%42?z=%41&utm_a=1&a").into_owned();
+        assert_eq!(normalized, "/a%2fb?a&z=A");
+        assert_eq!(key.normalize(&normalized), normalized);
+    }
+}
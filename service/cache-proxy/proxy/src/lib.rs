@@ -5,7 +5,7 @@ use std::{
     path::{Path, PathBuf},
     sync::Arc,
     task::Poll,
-    time::SystemTime,
+    time::{Instant, SystemTime},
 };
 
 use futures_util::{future::BoxFuture, FutureExt};
@@ -13,6 +13,8 @@ use http::{header, uri::Authority, Request, Response, Uri};
 use http_body_util::{BodyExt, Either, Empty, Full};
 use http_cache_semantics::{AfterResponse, BeforeRequest, CachePolicy};
 use hyper::body::{Bytes, Incoming};
+use ssri::Integrity;
+use tokio::sync::Semaphore;
 use tower_http::{
     classify::MakeClassifier,
     decompression::Decompression,
@@ -20,11 +22,46 @@ use tower_http::{
 };
 use tower_layer::Layer;
 use tower_service::Service;
+use tracing::Instrument;
 
+pub mod admin;
+pub mod archive;
+mod body;
+mod conditional;
 pub mod connector;
+mod encoding;
+mod entry;
+mod flight;
+pub mod gc;
+pub mod host;
+pub mod key;
+pub mod metrics;
+pub mod migrate;
+pub mod mirror;
+pub mod pin;
+pub mod prefetch;
+mod range;
+pub mod rewrite;
+pub mod rule;
+mod stale;
+pub mod tls;
+
+pub use body::{CachedBody, FillingBody, ForwardedBody, StoredBody};
+use body::{TeeBody, UpstreamRespBody};
+use encoding::Encoding;
+use entry::{BackgroundWriter, CacheEntry, EntryMetadata};
+use flight::{Flight, FlightGuard, InFlight};
+use gc::AccessLog;
+use key::KeyNormalization;
+use metrics::{CountBytes, HostMetrics, Lookup, Metrics, Revalidation};
+use mirror::{Mirror, Mirrors};
+use pin::Pins;
+use rewrite::HeaderRewrite;
+use rule::Rules;
+pub use stale::OfflineMode;
 
 fn should_cache_req<B>(req: &Request<B>) -> bool {
-    if req.method() != http::Method::GET {
+    if req.method() != http::Method::GET && req.method() != http::Method::HEAD {
         return false;
     }
     let h = req.headers();
@@ -38,28 +75,32 @@ fn should_cache_req<B>(req: &Request<B>) -> bool {
 pub enum ProxyError<E> {
     MissingHost,
     InvalidHost(header::HeaderValue, http::uri::InvalidUri),
-    UnexpectedHost(http::uri::Authority),
+    UnknownHost(http::uri::Authority),
     InvalidUri(http::uri::InvalidUriParts),
     InvalidPath(String, http::Error),
     Upstream(E),
     BoxedUpstream(tower_http::BoxError),
     ReadCache(cacache::Error),
     WriteCache(cacache::Error),
-    Decode(ciborium::de::Error<io::Error>),
+    /// Body of the pinned path does not match its integrity, or can't be verified.
+    Integrity(String),
+    /// Path is not cached, and upstream can't be contacted in offline mode.
+    Offline(String),
 }
 impl<E: Display> Display for ProxyError<E> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::MissingHost => f.write_str("missing host header"),
             Self::InvalidHost(h, e) => write!(f, "invalid host {h:?}: {e}"),
-            Self::UnexpectedHost(h) => write!(f, "unexpected host {h}"),
+            Self::UnknownHost(h) => write!(f, "host {h} is not proxied"),
             Self::InvalidUri(e) => write!(f, "invalid uri: {e}"),
             Self::InvalidPath(p, e) => write!(f, "invalid path {p:?}: {e}"),
             Self::Upstream(e) => write!(f, "failed to send request to upstream: {e}"),
             Self::BoxedUpstream(e) => write!(f, "failed to send request to upstream: {e}"),
             Self::ReadCache(e) => write!(f, "failed to read cache: {e}"),
             Self::WriteCache(e) => write!(f, "failed to write cache: {e}"),
-            Self::Decode(e) => write!(f, "failed to decode cache entry: {e}"),
+            Self::Integrity(p) => write!(f, "integrity check failed for {p:?}"),
+            Self::Offline(p) => write!(f, "{p:?} is not cached in offline mode"),
         }
     }
 }
@@ -68,36 +109,34 @@ impl<E: std::error::Error + 'static> std::error::Error for ProxyError<E> {
         match self {
             Self::MissingHost => None,
             Self::InvalidHost(_, e) => Some(e),
-            Self::UnexpectedHost(_) => None,
+            Self::UnknownHost(_) => None,
             Self::InvalidUri(e) => Some(e),
             Self::InvalidPath(_, e) => Some(e),
             Self::Upstream(s) => Some(s),
             Self::BoxedUpstream(e) => Some(e.as_ref()),
             Self::ReadCache(e) => Some(e),
             Self::WriteCache(e) => Some(e),
-            Self::Decode(e) => Some(e),
+            Self::Integrity(_) => None,
+            Self::Offline(_) => None,
         }
     }
 }
 
+/// Send `pts` to mirror `index` of upstream, with its `Host` as header.
 fn add_uri_authority<E>(
+    mirrors: &Mirrors,
+    index: usize,
     upstream_host: &Authority,
     mut pts: http::request::Parts,
 ) -> Result<http::request::Parts, ProxyError<E>> {
     let mut u = pts.uri.into_parts();
-    let host = pts
-        .headers
-        .get(header::HOST)
-        .ok_or(ProxyError::MissingHost)?;
-    let host = Authority::try_from(host.as_bytes())
-        .map_err(|e| ProxyError::InvalidHost(host.clone(), e))?;
-
-    if &host != upstream_host {
-        return Err(ProxyError::UnexpectedHost(host));
-    }
     u.scheme = Some(http::uri::Scheme::HTTPS);
-    u.authority = Some(host);
+    u.authority = Some(upstream_host.clone());
     pts.uri = Uri::from_parts(u).map_err(ProxyError::InvalidUri)?;
+    pts.headers.insert(
+        header::HOST,
+        header::HeaderValue::from_str(mirrors.host(index).as_str()).unwrap(),
+    );
     Ok(pts)
 }
 
@@ -105,21 +144,11 @@ fn add_uri_authority<E>(
 pub enum ProxyFuture<F, E> {
     Forward(#[pin] F),
     Boxed(BoxFuture<'static, Result<CachedResponse, ProxyError<E>>>),
-    Ready(Option<Result<CachedResponse, ProxyError<E>>>),
+    Ready(Option<Box<Result<CachedResponse, ProxyError<E>>>>),
 }
 impl<F, E> ProxyFuture<F, E> {
-    fn cached(mut pts: http::response::Parts, body: Bytes) -> Self {
-        pts.headers.insert(
-            header::CACHE_CONTROL,
-            header::HeaderValue::from_static("no-store"),
-        );
-        Self::Ready(Some(Ok(Response::from_parts(
-            pts,
-            Either::Right(Full::new(body)),
-        ))))
-    }
     fn ready_err(err: ProxyError<E>) -> Self {
-        Self::Ready(Some(Err(err)))
+        Self::Ready(Some(Box::new(Err(err))))
     }
 }
 impl<F, E> Future for ProxyFuture<F, E>
@@ -131,22 +160,34 @@ where
         match self.project() {
             Proj::Forward(f) => f.poll(cx),
             Proj::Boxed(b) => b.as_mut().poll(cx),
-            Proj::Ready(e) => Poll::Ready(e.take().unwrap()),
+            Proj::Ready(e) => Poll::Ready(*e.take().unwrap()),
         }
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
-struct CacheEntry {
-    policy: CachePolicy,
-    body: Bytes,
+/// Cache entry that is either already stored or being filled from upstream.
+enum Filled {
+    Stored(CacheEntry, FillOutcome),
+    Streaming(CachePolicy, FillingBody),
+    /// Stale response used because upstream can't be reached.
+    Stale(http::response::Parts, CacheEntry),
+}
+
+/// How a [`Filled::Stored`] entry is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FillOutcome {
+    /// Response is received from upstream and stored.
+    Updated,
+    /// Upstream confirmed the stored response, only its policy is updated.
+    NotModified,
+    /// Stored response is fresh, upstream is not requested.
+    Skipped,
 }
 
 type ClassifyEos = <HttpMakeClassifier as MakeClassifier>::ClassifyEos;
 type Classifier = <HttpMakeClassifier as MakeClassifier>::Classifier;
 
 pub type UpstreamBody = Either<Incoming, Empty<Bytes>>;
-pub type CachedBody = Either<tower_http::trace::ResponseBody<Incoming, ClassifyEos>, Full<Bytes>>;
 pub type CachedResponse = Response<CachedBody>;
 
 #[derive(Clone, Copy)]
@@ -166,8 +207,10 @@ impl<B> OnRequest<B> for ForwardOnRequest {
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct ForwardOnResponse {}
+#[derive(Clone)]
+pub struct ForwardOnResponse {
+    metrics: HostMetrics,
+}
 impl<B> OnResponse<B> for ForwardOnResponse {
     fn on_response(
         self,
@@ -175,6 +218,8 @@ impl<B> OnResponse<B> for ForwardOnResponse {
         latency: std::time::Duration,
         span: &tracing::Span,
     ) {
+        self.metrics
+            .upstream_latency(metrics::Upstream::Forward, latency);
         tower_http::trace::DefaultOnResponse::new()
             .level(tracing::Level::INFO)
             .include_headers(tracing::enabled!(tracing::Level::DEBUG))
@@ -198,29 +243,69 @@ impl<B> MakeSpan<B> for UpstreamMkSpan {
 pub struct CacheProxy<S> {
     root: Arc<Path>,
     authority: Arc<Authority>,
-    forwarded: Trace<S, HttpMakeClassifier, ForwardMkSpan, ForwardOnRequest, ForwardOnResponse>,
-    upstream: Decompression<Trace<S, HttpMakeClassifier, UpstreamMkSpan>>,
+    in_flight: InFlight,
+    offline: OfflineMode,
+    refresh: Arc<Semaphore>,
+    access: AccessLog,
+    pins: Pins,
+    rules: Rules,
+    key: Arc<KeyNormalization>,
+    headers: HeaderRewrite,
+    mirrors: Mirrors,
+    head_upstream: bool,
+    metrics: HostMetrics,
+    forwarded: Trace<
+        S,
+        HttpMakeClassifier,
+        ForwardMkSpan,
+        ForwardOnRequest,
+        ForwardOnResponse,
+        CountBytes,
+    >,
+    upstream: Decompression<
+        Trace<
+            S,
+            HttpMakeClassifier,
+            UpstreamMkSpan,
+            tower_http::trace::DefaultOnRequest,
+            tower_http::trace::DefaultOnResponse,
+            CountBytes,
+        >,
+    >,
 }
 
 type IncomingReq = Request<Incoming>;
 type IncomingResp = Response<Incoming>;
 
-type ForwardedBody = tower_http::trace::ResponseBody<Incoming, ClassifyEos>;
 type ForwardFn<E> = fn(Result<Response<ForwardedBody>, E>) -> Result<CachedResponse, ProxyError<E>>;
 type ForwardFuture<F, E> = futures_util::future::Map<
-    tower_http::trace::ResponseFuture<F, Classifier, ForwardOnResponse>,
+    tower_http::trace::ResponseFuture<F, Classifier, ForwardOnResponse, CountBytes>,
     ForwardFn<E>,
 >;
 
 impl<S: Clone> CacheProxy<S> {
-    fn with_path(root: Arc<Path>, authority: Arc<Authority>, upstream: S) -> Self {
+    fn with_layer(layer: &CacheLayer, upstream: S) -> Self {
+        let metrics = layer.metrics.host(layer.authority.as_str());
         Self {
-            root,
-            authority,
+            root: Arc::clone(&layer.root),
+            authority: Arc::clone(&layer.authority),
+            in_flight: layer.in_flight.clone(),
+            offline: layer.offline.clone(),
+            refresh: Arc::clone(&layer.refresh),
+            access: layer.access.clone(),
+            pins: layer.pins.normalized(&layer.key),
+            rules: layer.rules.clone(),
+            key: Arc::clone(&layer.key),
+            headers: layer.headers.clone(),
+            mirrors: layer.mirrors.clone(),
+            head_upstream: layer.head_upstream,
             forwarded: Trace::new_for_http(upstream.clone())
                 .make_span_with(ForwardMkSpan)
                 .on_request(ForwardOnRequest)
-                .on_response(ForwardOnResponse {}),
+                .on_response(ForwardOnResponse {
+                    metrics: metrics.clone(),
+                })
+                .on_body_chunk(metrics.count_bytes(metrics::Upstream::Forward)),
             upstream: Decompression::new(
                 Trace::new_for_http(upstream)
                     .make_span_with(UpstreamMkSpan)
@@ -231,24 +316,288 @@ impl<S: Clone> CacheProxy<S> {
                         tower_http::trace::DefaultOnResponse::new()
                             .level(tracing::Level::INFO)
                             .include_headers(true),
-                    ),
+                    )
+                    .on_body_chunk(metrics.count_bytes(metrics::Upstream::Fill)),
             ),
+            metrics,
         }
     }
     pub fn new(root: PathBuf, authority: Authority, upstream: S) -> Self {
-        Self::with_path(
-            Arc::from(root.into_boxed_path()),
-            Arc::new(authority),
-            upstream,
-        )
+        Self::with_layer(&CacheLayer::new(root, authority), upstream)
     }
 }
 impl<S> CacheProxy<S> {
-    fn write_entry(&self, key: &str, entry: &CacheEntry) -> Result<(), cacache::Error> {
-        let mut buf = Vec::new();
-        ciborium::into_writer(entry, &mut buf).unwrap();
-        cacache::write_sync(&self.root, key, buf)?;
-        Ok(())
+    /// Cache key of request `req`.
+    fn cache_key<'a>(&self, req: &'a http::request::Parts) -> std::borrow::Cow<'a, str> {
+        self.key.normalize(request_path(req))
+    }
+    /// Replace uri of request `req` with its cache key, so that requests
+    /// sharing an entry match its cache policy. The original uri is kept as
+    /// [`RequestUri`], which is sent to upstream.
+    pub(crate) fn normalize_uri(&self, req: &mut http::request::Parts) {
+        let key = self.cache_key(req);
+        if key == request_path(req) {
+            return;
+        }
+        match Uri::try_from(key.as_ref()) {
+            Ok(uri) => {
+                let orig = std::mem::replace(&mut req.uri, uri);
+                req.extensions.insert(RequestUri(orig));
+            }
+            Err(e) => tracing::warn!(key = %key, "cache key is not a valid uri: {e}"),
+        }
+    }
+    /// Store `body` of response with `metadata` under `key`.
+    async fn write_entry(
+        &mut self,
+        key: &str,
+        metadata: EntryMetadata,
+        body: Bytes,
+    ) -> Result<Integrity, cacache::Error> {
+        let (root, key) = (Arc::clone(&self.root), key.to_string());
+        blocking(move || entry::write_entry(&root, &key, &metadata, &body)).await
+    }
+    /// Find entry for request in the blocking thread pool, see [`entry::find_entry`].
+    async fn find_entry(
+        &mut self,
+        primary: &str,
+        headers: &http::HeaderMap,
+    ) -> Result<(String, Option<CacheEntry>), cacache::Error> {
+        let (root, primary, headers) =
+            (Arc::clone(&self.root), primary.to_string(), headers.clone());
+        blocking(move || entry::find_entry(&root, &primary, &headers)).await
+    }
+    /// Whether body of cached `entry` under `primary` matches the pinned integrity.
+    ///
+    /// Entries of paths that are not pinned always match. The body is only
+    /// read if the pin uses a different algorithm than the stored integrity.
+    async fn verify(&mut self, primary: &str, entry: &CacheEntry) -> bool {
+        let pin = match self.pins.get(primary) {
+            Some(pin) => pin.clone(),
+            None => return true,
+        };
+        let result = if pin.pick_algorithm() == entry.integrity.pick_algorithm() {
+            match pin.matches(&entry.integrity) {
+                Some(_) => Ok(()),
+                None => {
+                    Err(ssri::Error::IntegrityCheckError(pin, entry.integrity.clone()).to_string())
+                }
+            }
+        } else {
+            let (root, integrity) = (Arc::clone(&self.root), entry.integrity.clone());
+            blocking(move || {
+                let body = entry::read_body(&root, &integrity).map_err(|e| e.to_string())?;
+                pin.check(&body).map(|_| ()).map_err(|e| e.to_string())
+            })
+            .await
+        };
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!(
+                    key = primary,
+                    "cached body does not match pinned integrity: {e}"
+                );
+                false
+            }
+        }
+    }
+    /// Cached `entry` for client request `req`.
+    ///
+    /// Conditional requests are answered with `304 Not Modified` if the entry
+    /// matches, otherwise the body is streamed from disk in encoding accepted
+    /// by client, or only the requested ranges are sent if any.
+    async fn serve<E>(
+        &mut self,
+        req: &http::request::Parts,
+        mut pts: http::response::Parts,
+        entry: CacheEntry,
+    ) -> Result<CachedResponse, ProxyError<E>> {
+        if !pts.headers.contains_key(header::ETAG) {
+            pts.headers
+                .insert(header::ETAG, entry::etag(&entry.integrity));
+        }
+        // integrity of the full decoded body, even if it is encoded or only ranges are sent
+        let integrity = self
+            .pins
+            .get(&self.cache_key(req))
+            .unwrap_or(&entry.integrity);
+        if let Ok(v) = header::HeaderValue::from_str(&integrity.to_string()) {
+            pts.headers.insert(INTEGRITY, v);
+        }
+        let (integrity, size) = match self.encoded_body(req, &mut pts, &entry).await {
+            Some(encoded) => encoded,
+            None => (entry.integrity, entry.size),
+        };
+        if conditional::is_not_modified(&req.headers, &pts) {
+            tracing::debug!("cached response is not modified");
+            return Ok(cached_response(
+                conditional::not_modified(pts),
+                CachedBody::Full(Full::default()),
+            ));
+        }
+        range::accept_ranges(&mut pts);
+        // ranges are read from the identity body, which is never encoded for them
+        let parts = range::respond(&req.headers, &mut pts, size);
+        if is_head(req) {
+            // `Content-Length` of a partial response is set for its parts
+            if parts.is_none() {
+                pts.headers
+                    .insert(header::CONTENT_LENGTH, header::HeaderValue::from(size));
+            }
+            return Ok(cached_response(pts, CachedBody::Full(Full::default())));
+        }
+        let root = Arc::clone(&self.root);
+        let body = match parts {
+            Some(parts) => {
+                self.metrics
+                    .cache_sent(parts.iter().map(range::Part::len).sum::<u64>() as usize);
+                StoredBody::open_parts(root, integrity, parts)
+            }
+            None => {
+                self.metrics.cache_sent(size as usize);
+                StoredBody::open(root, integrity, size)
+            }
+        };
+        Ok(cached_response(pts, CachedBody::Stored(body)))
+    }
+    /// Integrity and size of the body of `entry` compressed in encoding
+    /// negotiated for `req`, headers of response `pts` are updated if it is used.
+    ///
+    /// Compressed bodies are memoized in cache, if it is not available yet,
+    /// compression is started in background and `None` is returned so that
+    /// identity is sent this time. Range requests always use identity.
+    async fn encoded_body(
+        &mut self,
+        req: &http::request::Parts,
+        pts: &mut http::response::Parts,
+        entry: &CacheEntry,
+    ) -> Option<(Integrity, u64)> {
+        if pts.status != http::StatusCode::OK
+            || req.headers.contains_key(header::RANGE)
+            || !encoding::is_compressible(&pts.headers, entry.size as usize)
+        {
+            return None;
+        }
+        encoding::add_vary(&mut pts.headers);
+        let enc = req.extensions.get::<Encoding>().copied()?;
+        let key = encoding::encoded_key(enc, &entry.integrity);
+        let (root, find_key) = (Arc::clone(&self.root), key.clone());
+        let found = blocking(move || {
+            Ok::<_, cacache::Error>(
+                cacache::index::find(&root, &find_key)?
+                    .and_then(|md| Some((gc::content_size(&root, &md.integrity)?, md.integrity))),
+            )
+        })
+        .await;
+        match found {
+            Ok(Some((size, integrity))) => {
+                tracing::debug!(encoding = enc.name(), "use compressed body");
+                self.access.record(&key);
+                if let Some(etag) = pts.headers.get(header::ETAG) {
+                    let etag = encoding::etag(etag, enc);
+                    pts.headers.insert(header::ETAG, etag);
+                }
+                pts.headers.insert(
+                    header::CONTENT_ENCODING,
+                    header::HeaderValue::from_static(enc.name()),
+                );
+                pts.headers.insert(header::CONTENT_LENGTH, size.into());
+                Some((integrity, size))
+            }
+            Ok(None) => {
+                self.compress(key, enc, entry.integrity.clone());
+                None
+            }
+            Err(e) => {
+                tracing::error!(key, "failed to read compressed body: {e}");
+                None
+            }
+        }
+    }
+    /// Compress body with `integrity` in `enc` and store it under `key` in a
+    /// blocking task.
+    fn compress(&self, key: String, enc: Encoding, integrity: Integrity) {
+        let flight = match self.in_flight.join(&key) {
+            Flight::Leader(g) => g,
+            Flight::Follower(_) => return,
+        };
+        let root = Arc::clone(&self.root);
+        let span = tracing::info_span!("compress", key);
+        tokio::task::spawn_blocking(move || {
+            let _enter = span.enter();
+            match compress_body(&root, &key, enc, &integrity) {
+                Ok((original, compressed)) => {
+                    tracing::info!(original, compressed, "compressed body stored")
+                }
+                Err(e) => tracing::error!("failed to compress body: {e}"),
+            }
+            drop(flight);
+        });
+    }
+    /// Stream upstream response body to client while writing it to cache.
+    ///
+    /// `req` is the request sent to upstream, if the response has `Vary`
+    /// header, it is stored as a variant selected by headers of `req`.
+    ///
+    /// Bodies of pinned paths are received completely and verified before
+    /// they are stored, nothing is stored or sent if they don't match.
+    async fn fill<E>(
+        &mut self,
+        req: &http::request::Parts,
+        policy: CachePolicy,
+        resp: &http::response::Parts,
+        body: UpstreamRespBody,
+        flight: FlightGuard,
+    ) -> Result<Filled, ProxyError<E>> {
+        let primary = self.cache_key(req).into_owned();
+        let key = match entry::vary_headers(&resp.headers) {
+            Some(vary) => {
+                tracing::debug!(?vary, "response varies on request headers");
+                let key = entry::variant_key(&primary, &vary, &req.headers);
+                let (root, primary) = (Arc::clone(&self.root), primary.clone());
+                blocking(move || entry::write_vary_index(&root, &primary, &vary))
+                    .await
+                    .map_err(ProxyError::WriteCache)?;
+                key
+            }
+            None => primary.clone(),
+        };
+        let metadata = entry_metadata(req, resp, &policy);
+        if let Some(pin) = self.pins.get(&primary) {
+            let body = body
+                .collect()
+                .await
+                .map_err(ProxyError::BoxedUpstream)?
+                .to_bytes();
+            if let Err(e) = pin.check(&body) {
+                tracing::error!(key, "upstream body does not match pinned integrity: {e}");
+                return Err(ProxyError::Integrity(primary));
+            }
+            let size = body.len() as u64;
+            let integrity = self
+                .write_entry(&key, metadata, body)
+                .await
+                .map_err(ProxyError::WriteCache)?;
+            let entry = CacheEntry {
+                policy,
+                integrity,
+                size,
+            };
+            drop(flight);
+            return Ok(Filled::Stored(entry, FillOutcome::Updated));
+        }
+        let expected_len = resp
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        let writer =
+            BackgroundWriter::spawn(Arc::clone(&self.root), key, metadata, expected_len, flight);
+        Ok(Filled::Streaming(
+            policy,
+            TeeBody::new(body, writer).spawn(Arc::clone(&self.root)),
+        ))
     }
 }
 impl<S> CacheProxy<S>
@@ -256,16 +605,32 @@ where
     S: Service<Request<UpstreamBody>, Response = IncomingResp>,
     S::Error: Display + 'static,
 {
+    /// Forward request to the preferred mirror of upstream, it is not retried
+    /// with other mirrors because the request body is consumed.
     fn forward(
         &mut self,
         req: IncomingReq,
     ) -> ProxyFuture<ForwardFuture<S::Future, S::Error>, S::Error> {
+        let (mut pts, body) = req.into_parts();
+        if self.offline.is_enabled() {
+            tracing::warn!("offline mode, request not forwarded to upstream");
+            return ProxyFuture::ready_err(ProxyError::Offline(request_path(&pts).to_string()));
+        }
+        self.headers.apply(&mut pts.headers);
+        // responses of HEAD requests have no body to verify
+        if pts.method != http::Method::HEAD && self.pins.get(&self.cache_key(&pts)).is_some() {
+            tracing::error!("response of pinned path can't be verified when forwarded");
+            return ProxyFuture::ready_err(ProxyError::Integrity(
+                self.cache_key(&pts).into_owned(),
+            ));
+        }
         tracing::warn!("forwarding request to upstream");
-        let (pts, body) = req.into_parts();
+        self.metrics.forwarded();
+        let (index, authority) = self.mirrors.preferred();
         ProxyFuture::Forward(
             self.forwarded
                 .call(Request::from_parts(
-                    match add_uri_authority(&self.authority, pts) {
+                    match add_uri_authority(&self.mirrors, index, &authority, pts) {
                         Ok(v) => v,
                         Err(e) => return ProxyFuture::ready_err(e),
                     },
@@ -274,138 +639,463 @@ where
                 .map(|r| match r {
                     Ok(resp) => {
                         let (pts, body) = resp.into_parts();
-                        Ok(Response::from_parts(pts, Either::Left(body)))
+                        Ok(Response::from_parts(pts, CachedBody::Forwarded(body)))
                     }
                     Err(e) => Err(ProxyError::Upstream(e)),
                 }),
         )
     }
-    fn cached_or_forward(
+    async fn cached_or_forward(
         &mut self,
-        entry: CacheEntry,
+        entry: Filled,
         orig_req: IncomingReq,
         req: http::request::Parts,
-    ) -> ProxyFuture<ForwardFuture<S::Future, S::Error>, S::Error> {
-        match entry.policy.before_request(&req, SystemTime::now()) {
+    ) -> Result<CachedResponse, ProxyError<S::Error>> {
+        let now = SystemTime::now();
+        let (result, body) = match entry {
+            Filled::Stored(entry, _) => {
+                (entry.policy.before_request(&req, now), Either::Left(entry))
+            }
+            Filled::Streaming(policy, body) => {
+                (policy.before_request(&req, now), Either::Right(body))
+            }
+            Filled::Stale(pts, entry) => return self.serve(&req, pts, entry).await,
+        };
+        match result {
             BeforeRequest::Fresh(pts) => {
                 tracing::debug!("using response from cache");
-                ProxyFuture::cached(pts, entry.body)
+                match body {
+                    Either::Left(entry) => self.serve(&req, pts, entry).await,
+                    // the body is still filled after it is dropped
+                    Either::Right(_) if is_head(&req) => {
+                        tracing::debug!("head request, filling cache in background");
+                        Ok(cached_response(pts, CachedBody::Full(Full::default())))
+                    }
+                    Either::Right(_) if conditional::is_not_modified(&req.headers, &pts) => {
+                        tracing::debug!("response is not modified, filling cache in background");
+                        Ok(cached_response(
+                            conditional::not_modified(pts),
+                            CachedBody::Full(Full::default()),
+                        ))
+                    }
+                    // ranges are not served while filling, the full response is sent
+                    Either::Right(body) => Ok(cached_response(pts, CachedBody::Filling(body))),
+                }
             }
             BeforeRequest::Stale { .. } => {
                 tracing::warn!("cached response can't be used, forward request to upstream");
-                self.forward(orig_req)
+                drop(body);
+                self.forward(orig_req).await
             }
         }
     }
+    /// Request sent to upstream to fill cache entry for normalized client request.
+    ///
+    /// Headers of client request are kept so that responses varying on them
+    /// are stored correctly, except for conditional and range requests
+    /// and cache directives, which are only meaningful to the client.
+    fn upstream_request(
+        &self,
+        req: &http::request::Parts,
+    ) -> Result<http::request::Parts, ProxyError<S::Error>> {
+        let mut upstream_req = Request::get(req.uri.clone())
+            .body(())
+            .map_err(|e| ProxyError::InvalidPath(req.uri.to_string(), e))?
+            .into_parts()
+            .0;
+        upstream_req.headers = req
+            .headers
+            .iter()
+            .filter(|(h, _)| !CLIENT_ONLY_HEADERS.contains(h))
+            .map(|(h, v)| (h.clone(), v.clone()))
+            .collect();
+        self.headers.apply(&mut upstream_req.headers);
+        upstream_req.headers.insert(
+            header::HOST,
+            header::HeaderValue::from_str(self.authority.as_str()).unwrap(),
+        );
+        if let Some(uri) = req.extensions.get::<RequestUri>() {
+            upstream_req.extensions.insert(uri.clone());
+        }
+        Ok(upstream_req)
+    }
+    /// Send `req` to mirrors of upstream in order, until one of them responds
+    /// without server error.
+    ///
+    /// The mirror of the response is recorded as [`Mirror`] in its extensions.
+    /// If all mirrors fail, the last response or error is returned.
     async fn req_upstream(
         &mut self,
         mut req: http::request::Parts,
-    ) -> Result<(http::response::Parts, Bytes), ProxyError<S::Error>> {
-        {
-            let mut uri = req.uri.into_parts();
-            uri.scheme = Some(http::uri::Scheme::HTTPS);
-            uri.authority = Some(self.authority.as_ref().clone());
-            req.uri = Uri::from_parts(uri).map_err(ProxyError::InvalidUri)?;
+    ) -> Result<(http::response::Parts, UpstreamRespBody), ProxyError<S::Error>> {
+        if let Some(RequestUri(uri)) = req.extensions.remove() {
+            req.uri = uri;
         }
-        req.headers
-            .insert(header::USER_AGENT, header::HeaderValue::from_static("curl"));
-        let (mut pts, body) = self
-            .upstream
-            .call(Request::from_parts(req, Either::Right(Empty::new())))
-            .await
-            .map_err(ProxyError::Upstream)?
-            .into_parts();
+        let rules = self.rules.clone();
+        let rule = rules.find(req.uri.path()).map(|r| (r, req.clone()));
+        let mirrors = self.mirrors.clone();
+        let order = mirrors.order();
+        let mut result = None;
+        for (i, (index, authority)) in order.iter().enumerate() {
+            let req = add_uri_authority(&mirrors, *index, authority, req.clone())?;
+            let start = Instant::now();
+            let resp = self
+                .upstream
+                .call(Request::from_parts(req, Either::Right(Empty::new())))
+                .await;
+            match resp {
+                Ok(resp) => {
+                    self.metrics
+                        .upstream_latency(metrics::Upstream::Fill, start.elapsed());
+                    let failed = resp.status().is_server_error();
+                    result = Some(Ok((authority, resp)));
+                    if !failed {
+                        mirrors.success(*index);
+                        break;
+                    }
+                    mirrors.failure(*index);
+                }
+                Err(e) => {
+                    mirrors.failure(*index);
+                    result = Some(Err(ProxyError::Upstream(e)));
+                }
+            }
+            if i + 1 < order.len() {
+                tracing::warn!(mirror = %authority, "mirror failed, trying next mirror");
+            }
+        }
+        let Some(result) = result else {
+            // no mirror to send the request to
+            return Err(ProxyError::UnknownHost((*self.authority).clone()));
+        };
+        let (authority, resp) = result?;
+        let (mut pts, body) = resp.into_parts();
         pts.headers.remove(header::CONTENT_ENCODING);
-        Ok((
-            pts,
-            body.collect()
-                .await
-                .map_err(ProxyError::BoxedUpstream)?
-                .to_bytes(),
-        ))
+        pts.extensions.insert(Mirror(authority.clone()));
+        if let Some((rule, req)) = rule {
+            tracing::debug!(rule = rule.path, "overriding cache policy of response");
+            rule.apply(&req, &mut pts);
+        }
+        Ok((pts, body))
     }
     async fn update_entry(
         &mut self,
         key: &str,
         entry: CacheEntry,
-    ) -> Result<CacheEntry, ProxyError<S::Error>> {
-        match entry.policy.before_request(
-            &Request::get(key)
-                .header(header::HOST, self.authority.as_str())
-                .body(())
-                .map_err(|e| ProxyError::InvalidPath(key.to_string(), e))?,
-            SystemTime::now(),
-        ) {
+        upstream_req: http::request::Parts,
+        flight: FlightGuard,
+    ) -> Result<Filled, ProxyError<S::Error>> {
+        let before = if upstream_req.extensions.get::<ForceRevalidation>().is_some() {
+            // the entry is made stale for the request, which is not sent upstream
+            let mut forced = upstream_req.clone();
+            forced.headers.insert(
+                header::CACHE_CONTROL,
+                header::HeaderValue::from_static("no-cache"),
+            );
+            match entry.policy.before_request(&forced, SystemTime::now()) {
+                BeforeRequest::Stale {
+                    mut request,
+                    matches,
+                } => {
+                    request.headers.remove(header::CACHE_CONTROL);
+                    for v in upstream_req.headers.get_all(header::CACHE_CONTROL) {
+                        request.headers.append(header::CACHE_CONTROL, v.clone());
+                    }
+                    BeforeRequest::Stale { request, matches }
+                }
+                fresh => fresh,
+            }
+        } else {
+            entry
+                .policy
+                .before_request(&upstream_req, SystemTime::now())
+        };
+        match before {
             BeforeRequest::Fresh(_) => {
                 tracing::warn!("cached response is fresh but can't be used");
-                Ok(entry)
+                Ok(Filled::Stored(entry, FillOutcome::Skipped))
             }
-            BeforeRequest::Stale { request, .. } => {
+            BeforeRequest::Stale { mut request, .. } => {
                 tracing::info!("revalidating cached response");
-                let (resp, upd_body) = self.req_upstream(request.clone()).await?;
-                let entry = match entry
+                // it has uri of the stored response
+                request.uri = upstream_req.uri.clone();
+                request.extensions = upstream_req.extensions.clone();
+                let upstream = self.req_upstream(request.clone()).await;
+                let failed = match &upstream {
+                    Ok((resp, _)) if resp.status.is_server_error() => {
+                        tracing::warn!(status = %resp.status, "upstream responded with error");
+                        true
+                    }
+                    Err(e @ (ProxyError::Upstream(_) | ProxyError::BoxedUpstream(_))) => {
+                        tracing::warn!("{e}");
+                        true
+                    }
+                    _ => false,
+                };
+                if failed {
+                    if let Some(pts) =
+                        stale::on_error(&entry.policy, &upstream_req, SystemTime::now())
+                    {
+                        tracing::warn!("failed to revalidate, using stale response");
+                        self.metrics.revalidation(Revalidation::Failed);
+                        return Ok(Filled::Stale(pts, entry));
+                    }
+                }
+                let (resp, upd_body) = upstream?;
+                match entry
                     .policy
                     .after_response(&request, &resp, SystemTime::now())
                 {
                     AfterResponse::Modified(cp, _) => {
                         tracing::debug!("response is updated");
-                        CacheEntry {
-                            policy: cp,
-                            body: upd_body,
-                        }
+                        self.metrics.revalidation(Revalidation::Modified);
+                        self.fill(&request, cp, &resp, upd_body, flight).await
                     }
                     AfterResponse::NotModified(cp, _) => {
                         tracing::debug!("response is not modified");
-                        CacheEntry {
+                        self.metrics.revalidation(Revalidation::NotModified);
+                        // the body is kept, only the policy is updated
+                        let metadata = entry_metadata(&request, &resp, &cp);
+                        let entry = CacheEntry {
                             policy: cp,
-                            body: entry.body,
-                        }
+                            ..entry
+                        };
+                        let (root, key, stored) =
+                            (Arc::clone(&self.root), key.to_string(), entry.clone());
+                        blocking(move || entry::update_metadata(&root, &key, &metadata, &stored))
+                            .await
+                            .map_err(ProxyError::WriteCache)?;
+                        Ok(Filled::Stored(entry, FillOutcome::NotModified))
                     }
-                };
-                self.write_entry(key, &entry)
-                    .map_err(ProxyError::WriteCache)?;
-                Ok(entry)
+                }
             }
         }
     }
     async fn get_missing(
         &mut self,
         key: &str,
-        uri: &Uri,
-    ) -> Result<CacheEntry, ProxyError<S::Error>> {
+        upstream_req: http::request::Parts,
+        flight: FlightGuard,
+    ) -> Result<Filled, ProxyError<S::Error>> {
         tracing::info!(key, "get response from remote");
-        let upstream_req = Request::get(uri)
-            .header(header::HOST, self.authority.as_str())
-            .body(())
-            .map_err(|e| ProxyError::InvalidPath(uri.to_string(), e))?
-            .into_parts()
-            .0;
         let (pts, body) = self.req_upstream(upstream_req.clone()).await?;
-        let entry = CacheEntry {
-            policy: CachePolicy::new(&upstream_req, &pts),
+        self.fill(
+            &upstream_req,
+            CachePolicy::new(&upstream_req, &pts),
+            &pts,
             body,
-        };
-        self.write_entry(key, &entry)
-            .map_err(ProxyError::WriteCache)?;
-        Ok(entry)
+            flight,
+        )
+        .await
+    }
+}
+
+/// Response header with subresource integrity of cached body.
+const INTEGRITY: header::HeaderName = header::HeaderName::from_static("integrity");
+
+/// Request headers that are not sent to upstream when filling cache.
+const CLIENT_ONLY_HEADERS: [header::HeaderName; 12] = [
+    header::ACCEPT_ENCODING,
+    header::CACHE_CONTROL,
+    header::PRAGMA,
+    header::IF_MATCH,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::IF_UNMODIFIED_SINCE,
+    header::IF_RANGE,
+    header::RANGE,
+    header::CONNECTION,
+    header::TE,
+    header::UPGRADE,
+];
+
+/// Marks normalized request of client `HEAD` request.
+#[derive(Clone, Copy)]
+struct HeadRequest;
+
+/// Uri of client request, whose normalized request has its cache key as uri.
+#[derive(Clone)]
+struct RequestUri(Uri);
+
+/// Marks upstream request revalidating the entry even if it is fresh.
+#[derive(Clone, Copy)]
+pub(crate) struct ForceRevalidation;
+
+/// Compress body with `integrity` in `enc` and store it under `key`,
+/// streaming it from cache through the encoder back into cache.
+///
+/// Returns sizes of the original and the compressed body.
+fn compress_body(
+    root: &Path,
+    key: &str,
+    enc: Encoding,
+    integrity: &Integrity,
+) -> Result<(u64, u64), cacache::Error> {
+    let mut reader = cacache::SyncReader::open_hash(root, integrity.clone())?;
+    let writer = cacache::WriteOpts::new().open_sync(root, key)?;
+    let mut encoder = enc
+        .encoder(writer)
+        .map_err(|e| cacache::Error::IoError(e, "failed to start encoder".into()))?;
+    let original = io::copy(&mut reader, &mut encoder)
+        .map_err(|e| cacache::Error::IoError(e, "failed to compress body".into()))?;
+    reader.check()?;
+    let writer = encoder
+        .finish()
+        .map_err(|e| cacache::Error::IoError(e, "failed to finish compressed body".into()))?;
+    let compressed = writer.commit()?;
+    Ok((
+        original,
+        gc::content_size(root, &compressed).unwrap_or_default(),
+    ))
+}
+
+/// Response of cached body, which must not be stored by client again.
+fn cached_response(mut pts: http::response::Parts, body: CachedBody) -> CachedResponse {
+    pts.headers.insert(
+        header::CACHE_CONTROL,
+        header::HeaderValue::from_static("no-store"),
+    );
+    Response::from_parts(pts, body)
+}
+
+/// Run cache I/O `f` in the blocking thread pool, so that it doesn't stall
+/// other requests.
+pub(crate) async fn blocking<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let span = tracing::Span::current();
+    match tokio::task::spawn_blocking(move || span.in_scope(f)).await {
+        Ok(v) => v,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
     }
 }
 
-fn cache_key(req: &http::request::Parts) -> &str {
-    req.uri.path_and_query().map_or("", |p| p.as_str())
+/// Whether normalized request `req` is of client `HEAD` request, whose
+/// response has no body.
+fn is_head(req: &http::request::Parts) -> bool {
+    req.extensions.get::<HeadRequest>().is_some()
+}
+
+/// Path and query of request `req`, as sent to upstream.
+fn request_path(req: &http::request::Parts) -> &str {
+    let uri = req
+        .extensions
+        .get::<RequestUri>()
+        .map_or(&req.uri, |u| &u.0);
+    uri.path_and_query().map_or("", |p| p.as_str())
 }
 
-pub struct CacheLayer(Arc<Path>, Arc<Authority>);
+/// Index metadata of entry storing response `resp` of upstream request `req`
+/// with `policy`.
+fn entry_metadata(
+    req: &http::request::Parts,
+    resp: &http::response::Parts,
+    policy: &CachePolicy,
+) -> EntryMetadata {
+    EntryMetadata {
+        uri: Some(request_path(req).to_string()),
+        mirror: resp.extensions.get::<Mirror>().map(|m| m.0.to_string()),
+        policy: Some(policy.clone()),
+    }
+}
+
+/// Default limit of concurrent background revalidations.
+pub const DEFAULT_MAX_REFRESH: usize = 16;
+
+pub struct CacheLayer {
+    root: Arc<Path>,
+    authority: Arc<Authority>,
+    in_flight: InFlight,
+    offline: OfflineMode,
+    refresh: Arc<Semaphore>,
+    access: AccessLog,
+    pins: Pins,
+    rules: Rules,
+    key: Arc<KeyNormalization>,
+    headers: HeaderRewrite,
+    mirrors: Mirrors,
+    head_upstream: bool,
+    metrics: Metrics,
+}
 impl CacheLayer {
     pub fn new(root: PathBuf, authority: Authority) -> Self {
-        Self(Arc::from(root.into_boxed_path()), Arc::new(authority))
+        Self {
+            root: Arc::from(root.into_boxed_path()),
+            mirrors: Mirrors::single(authority.clone()),
+            authority: Arc::new(authority),
+            in_flight: InFlight::default(),
+            offline: OfflineMode::default(),
+            refresh: Arc::new(Semaphore::new(DEFAULT_MAX_REFRESH)),
+            access: AccessLog::default(),
+            pins: Pins::default(),
+            rules: Rules::default(),
+            key: Arc::default(),
+            headers: HeaderRewrite::default(),
+            head_upstream: false,
+            metrics: Metrics::default(),
+        }
+    }
+    /// Use `offline` to switch offline mode of proxies created by this layer.
+    pub fn offline(mut self, offline: OfflineMode) -> Self {
+        self.offline = offline;
+        self
+    }
+    /// Revalidate at most `max` stale entries in background at the same time.
+    pub fn max_refresh(mut self, max: usize) -> Self {
+        self.refresh = Arc::new(Semaphore::new(max));
+        self
+    }
+    /// Record access of cache entries to `access`, which is used for eviction.
+    pub fn access_log(mut self, access: AccessLog) -> Self {
+        self.access = access;
+        self
+    }
+    /// Verify bodies of paths pinned in `pins` before they are stored or served.
+    pub fn pins(mut self, pins: Pins) -> Self {
+        self.pins = pins;
+        self
+    }
+    /// Override cache policy of upstream responses by `rules`.
+    pub fn rules(mut self, rules: Rules) -> Self {
+        self.rules = rules;
+        self
+    }
+    /// Normalize path and query of requests by `key` into cache keys.
+    pub fn key_normalization(mut self, key: KeyNormalization) -> Self {
+        self.key = Arc::new(key);
+        self
+    }
+    /// Rewrite headers of requests sent to upstream by `headers`.
+    pub fn upstream_headers(mut self, headers: HeaderRewrite) -> Self {
+        self.headers = headers;
+        self
+    }
+    /// Fetch responses from `mirrors` of the host, instead of the host itself.
+    ///
+    /// Responses of all mirrors share the cache of the host.
+    pub fn mirrors(mut self, mirrors: Mirrors) -> Self {
+        self.mirrors = mirrors;
+        self
+    }
+    /// Forward `HEAD` requests of uncached paths to upstream without storing
+    /// the response, instead of filling the cache with a `GET` request.
+    pub fn head_upstream(mut self, enabled: bool) -> Self {
+        self.head_upstream = enabled;
+        self
+    }
+    /// Record metrics of proxies created by this layer to `metrics`.
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
     }
 }
 
 impl<S: Clone> Layer<S> for CacheLayer {
     type Service = CacheProxy<S>;
     fn layer(&self, inner: S) -> Self::Service {
-        CacheProxy::with_path(Arc::clone(&self.0), Arc::clone(&self.1), inner)
+        CacheProxy::with_layer(self, inner)
     }
 }
 
@@ -429,64 +1119,256 @@ where
         if !should_cache_req(&req) {
             return self.forward(req);
         }
+        let rules = self.rules.clone();
+        let rule = rules.find(req.uri().path());
+        if let Some(rule) = rule {
+            tracing::info!(rule = rule.path, "cache rule matched");
+            if rule.bypass {
+                return self.forward(req);
+            }
+        }
         let (req, orig_req) = {
             let (pts, body) = req.into_parts();
 
             let mut norm_pts = pts.clone();
             norm_pts.headers.remove(header::ACCEPT_ENCODING);
+            // variants are selected by headers sent to upstream
+            self.headers.apply(&mut norm_pts.headers);
+            if rule.is_some_and(|r| r.immutable) {
+                // client can't force revalidation of immutable responses
+                norm_pts.headers.remove(header::CACHE_CONTROL);
+                norm_pts.headers.remove(header::PRAGMA);
+            }
+            // kept for choosing encoding of cached body
+            if let Some(enc) = Encoding::negotiate(&pts.headers) {
+                norm_pts.extensions.insert(enc);
+            }
+            // answered from cached GET response
+            if norm_pts.method == http::Method::HEAD {
+                norm_pts.method = http::Method::GET;
+                norm_pts.extensions.insert(HeadRequest);
+            }
+            self.normalize_uri(&mut norm_pts);
 
             (norm_pts, Request::from_parts(pts, body))
         };
-        tracing::debug!(key = cache_key(&req), "cache key");
+        tracing::debug!(key = %self.cache_key(&req), "cache key");
         tracing::debug!(req = ?req, "normalized request");
 
-        match cacache::read_sync(&self.root, cache_key(&req)) {
-            Ok(v) => {
-                let entry: CacheEntry = match ciborium::from_reader(v.as_slice()) {
-                    Ok(v) => v,
-                    Err(e) => return ProxyFuture::ready_err(ProxyError::Decode(e)),
-                };
-                if !entry.policy.is_storable() {
-                    tracing::warn!("request is not storable");
-                    return self.forward(orig_req);
+        self.lookup(req, orig_req)
+    }
+}
+
+impl<S, E> CacheProxy<S>
+where
+    S: Clone + Send + 'static,
+    S: Service<Request<UpstreamBody>, Response = Response<Incoming>, Error = E>,
+    S::Future: Send,
+    E: Display + Send + 'static,
+{
+    /// Serve normalized request from cache, fetching or revalidating the entry if needed.
+    fn lookup(
+        &mut self,
+        req: http::request::Parts,
+        orig_req: IncomingReq,
+    ) -> ProxyFuture<ForwardFuture<S::Future, E>, E> {
+        let mut cloned_self = self.clone();
+        ProxyFuture::Boxed(
+            async move { cloned_self.lookup_entry(req, orig_req).await }
+                .in_current_span()
+                .boxed(),
+        )
+    }
+    async fn lookup_entry(
+        &mut self,
+        req: http::request::Parts,
+        orig_req: IncomingReq,
+    ) -> Result<CachedResponse, ProxyError<E>> {
+        let primary = self.cache_key(&req).into_owned();
+        let (key, entry) = match self
+            .find_entry(&primary, &req.headers)
+            .await
+            .map_err(ProxyError::ReadCache)?
+        {
+            (key, Some(entry)) => (key, entry),
+            (_, None) if is_head(&req) && self.head_upstream => {
+                tracing::info!("head request of missing entry, forwarding");
+                return self.forward(orig_req).await;
+            }
+            (key, None) => return self.fetch(key, None, req, orig_req).await,
+        };
+        if !self.verify(&primary, &entry).await {
+            return self.fetch(key, None, req, orig_req).await;
+        }
+        self.access.record(&primary);
+        if key != primary {
+            tracing::debug!(key, "using variant of cached response");
+            self.access.record(&key);
+        }
+        if self.offline.is_enabled() {
+            let pts = stale::offline(&entry.policy, &req, SystemTime::now())
+                .ok_or_else(|| ProxyError::Offline(request_path(&req).to_string()))?;
+            tracing::info!("offline mode, use cached response");
+            self.metrics.lookup(Lookup::Offline);
+            return self.serve(&req, pts, entry).await;
+        }
+        if !entry.policy.is_storable() {
+            tracing::warn!("request is not storable");
+            self.metrics.lookup(Lookup::Uncacheable);
+            return self.forward(orig_req).await;
+        }
+        match entry.policy.before_request(&req, SystemTime::now()) {
+            BeforeRequest::Fresh(pts) => {
+                tracing::debug!("use cached response");
+                self.metrics.lookup(Lookup::Hit);
+                self.serve(&req, pts, entry).await
+            }
+            BeforeRequest::Stale { matches: false, .. } if key != primary => {
+                tracing::info!("cached variant does not match request, refetching");
+                self.fetch(key, None, req, orig_req).await
+            }
+            BeforeRequest::Stale { matches: false, .. } => {
+                tracing::warn!("cached response does not match request");
+                self.metrics.lookup(Lookup::Uncacheable);
+                self.forward(orig_req).await
+            }
+            BeforeRequest::Stale { matches: true, .. } => {
+                match stale::while_revalidate(&entry.policy, &req, SystemTime::now()) {
+                    Some(pts) => {
+                        tracing::info!("use stale response while revalidating");
+                        self.metrics.lookup(Lookup::Stale);
+                        self.refresh(key, entry.clone(), &req);
+                        self.serve(&req, pts, entry).await
+                    }
+                    None => self.fetch(key, Some(entry), req, orig_req).await,
                 }
-                match entry.policy.before_request(&req, SystemTime::now()) {
-                    BeforeRequest::Fresh(pts) => {
-                        tracing::debug!("use cached response");
-                        ProxyFuture::cached(pts, entry.body)
+            }
+        }
+    }
+    /// Revalidate `entry` in a background task.
+    ///
+    /// Nothing is done if the entry is already being updated, or too many
+    /// entries are being revalidated in background.
+    fn refresh(&self, key: String, entry: CacheEntry, req: &http::request::Parts) {
+        let flight = match self.in_flight.join(&key) {
+            Flight::Leader(g) => g,
+            Flight::Follower(_) => {
+                tracing::debug!(key, "entry is already being updated");
+                return;
+            }
+        };
+        let permit = match Arc::clone(&self.refresh).try_acquire_owned() {
+            Ok(p) => p,
+            Err(_) => {
+                tracing::warn!(key, "too many background revalidations, skipped");
+                return;
+            }
+        };
+        let upstream_req = match self.upstream_request(req) {
+            Ok(r) => r,
+            Err(e) => {
+                tracing::error!("{e}");
+                return;
+            }
+        };
+        let span = tracing::info_span!(parent: None, "refresh", key);
+        span.follows_from(tracing::Span::current());
+        let mut cloned_self = self.clone();
+        tokio::spawn(
+            async move {
+                match cloned_self
+                    .update_entry(&key, entry, upstream_req, flight)
+                    .await
+                {
+                    Ok(Filled::Stored(_, outcome)) => {
+                        tracing::info!(?outcome, "cached response revalidated")
                     }
-                    BeforeRequest::Stale { matches: false, .. } => {
-                        tracing::warn!("cached response does not match request");
-                        self.forward(orig_req)
+                    Ok(Filled::Streaming(_, body)) => {
+                        if !body.drain().await {
+                            tracing::warn!("updated response is not stored")
+                        }
                     }
-                    BeforeRequest::Stale { matches: true, .. } => {
-                        let mut cloned_self = self.clone();
-                        ProxyFuture::Boxed(
-                            async move {
-                                let key = cache_key(&req);
-                                let entry = cloned_self.update_entry(key, entry).await?;
-                                cloned_self.cached_or_forward(entry, orig_req, req).await
-                            }
-                            .boxed(),
-                        )
+                    Ok(Filled::Stale(_, _)) => {
+                        tracing::warn!("failed to revalidate, keep stale response")
                     }
+                    Err(e) => tracing::error!("failed to revalidate: {e}"),
                 }
+                drop(permit);
             }
-            Err(cacache::Error::EntryNotFound(_, _)) => {
-                let mut cloned_self = self.clone();
-                ProxyFuture::Boxed(
-                    async move {
-                        /* if request authority does not match self.authority,
-                           a request to upstream is still sent, but response will
-                           not be used and return an error
-                        */
-                        let entry = cloned_self.get_missing(cache_key(&req), &req.uri).await?;
-                        cloned_self.cached_or_forward(entry, orig_req, req).await
+            .instrument(span),
+        );
+    }
+    /// Get response of `key` from upstream, revalidating `entry` if there is one.
+    ///
+    /// If another request of the same key is in progress, wait for it
+    /// and lookup cache again.
+    fn fetch(
+        &mut self,
+        key: String,
+        entry: Option<CacheEntry>,
+        req: http::request::Parts,
+        orig_req: IncomingReq,
+    ) -> ProxyFuture<ForwardFuture<S::Future, E>, E> {
+        if self.offline.is_enabled() {
+            tracing::warn!(key, "offline mode, response is not cached");
+            return ProxyFuture::ready_err(ProxyError::Offline(request_path(&req).to_string()));
+        }
+        let mut cloned_self = self.clone();
+        ProxyFuture::Boxed(
+            async move {
+                let flight = match cloned_self.in_flight.join(&key) {
+                    Flight::Leader(g) => g,
+                    Flight::Follower(f) => {
+                        f.wait().await;
+                        return cloned_self.lookup(req, orig_req).await;
+                    }
+                };
+                // followers are counted when they lookup cache again
+                cloned_self.metrics.lookup(match entry {
+                    Some(_) => Lookup::Expired,
+                    None => Lookup::Miss,
+                });
+                let upstream_req = cloned_self.upstream_request(&req)?;
+                let entry = match entry {
+                    Some(entry) => {
+                        cloned_self
+                            .update_entry(&key, entry, upstream_req, flight)
+                            .await?
                     }
-                    .boxed(),
-                )
+                    None => cloned_self.get_missing(&key, upstream_req, flight).await?,
+                };
+                cloned_self.cached_or_forward(entry, orig_req, req).await
             }
-            Err(e) => ProxyFuture::ready_err(ProxyError::ReadCache(e)),
-        }
+            .boxed(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::*;
+
+    #[test]
+    fn mirror_host_header() {
+        let authority = |s| Authority::from_static(s);
+        let mirrors = Mirrors::with_hosts(vec![
+            (authority("cdn.example.com"), authority("cdn.example.com")),
+            // ip=name mirror
+            (authority("10.0.0.1"), authority("cdn.example.com")),
+        ])
+        .unwrap();
+        let (pts, _) = Request::get("/a.js?v=1").body(()).unwrap().into_parts();
+        let pts =
+            add_uri_authority::<Infallible>(&mirrors, 1, &authority("10.0.0.1"), pts).unwrap();
+        assert_eq!(pts.uri, "https://10.0.0.1/a.js?v=1");
+        assert_eq!(pts.headers[header::HOST], "cdn.example.com");
+    }
+
+    #[test]
+    fn no_mirrors() {
+        assert!(Mirrors::new(Vec::new()).is_err());
+        assert!(Mirrors::with_hosts(Vec::new()).is_err());
     }
 }
@@ -1,19 +1,42 @@
 use std::{
-    fmt::Display, fs::Permissions, os::unix::fs::PermissionsExt, path::PathBuf, process::ExitCode,
+    collections::HashMap,
+    fmt::Display,
+    fs::Permissions,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::ExitCode,
     str::FromStr,
+    time::Duration,
 };
 
 use anyhow::Context;
 use bytes::Bytes;
 use clap::{Arg, ArgGroup, Args, FromArgMatches, Parser};
-use http::{header, uri::Authority, Request, Response, StatusCode};
-use http_body_util::{Either, Full};
+use futures_util::StreamExt;
+use http::{header, uri::Authority, Request, Response, StatusCode, Uri};
+use http_body_util::Full;
 use hyper::{
     body::{Body, Incoming},
     rt::{Read, Write},
 };
 use hyper_util::rt::{TokioExecutor, TokioIo};
-use local_cdn_proxy::{CachedResponse, ProxyError};
+use local_cdn_proxy::{
+    archive::{self, Existing, ExportFilter},
+    connector::{Connector, Egress, ProxyAuth, ServerNames, Tunnel},
+    gc::{AccessLog, Eviction, GcConfig},
+    host::{host_root, HostLayer},
+    key::KeyNormalization,
+    metrics::Metrics,
+    migrate,
+    mirror::{Mirrors, NoMirrors},
+    pin::Pins,
+    rewrite::HeaderRewrite,
+    rule::Rules,
+    tls::TlsConfig,
+    CachedBody, CachedResponse, OfflineMode, ProxyError,
+};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tower_layer::Layer;
 use tracing::Instrument;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -31,6 +54,139 @@ impl Display for LogOutput {
         })
     }
 }
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+enum EvictionArg {
+    #[default]
+    Lru,
+    Lfu,
+}
+impl Display for EvictionArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Lru => "lru",
+            Self::Lfu => "lfu",
+        })
+    }
+}
+impl From<EvictionArg> for Eviction {
+    fn from(value: EvictionArg) -> Self {
+        match value {
+            EvictionArg::Lru => Self::Lru,
+            EvictionArg::Lfu => Self::Lfu,
+        }
+    }
+}
+
+/// Parse size in bytes with optional `K`, `M`, `G` or `T` suffix.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let shift = match unit
+        .trim()
+        .to_ascii_uppercase()
+        .trim_end_matches(['B', 'I'])
+    {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        u => return Err(format!("unknown size unit {u:?}")),
+    };
+    num.parse::<u64>()
+        .map_err(|e| e.to_string())?
+        .checked_mul(1 << shift)
+        .ok_or_else(|| "size too large".to_string())
+}
+
+/// Parse duration in seconds with optional `s`, `m`, `h` or `d` suffix.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let secs = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        u => return Err(format!("unknown duration unit {u:?}")),
+    };
+    num.parse::<u64>()
+        .map_err(|e| e.to_string())?
+        .checked_mul(secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| "duration too large".to_string())
+}
+
+/// Upstream origin, with TLS server name if it is not the host name.
+#[derive(Debug, Clone)]
+struct Origin {
+    authority: Authority,
+    server_name: Option<ServerName<'static>>,
+}
+impl Origin {
+    /// `Host` of requests sent to the origin, its server name with the port
+    /// of its authority if it is set.
+    fn host(&self) -> Result<Authority, String> {
+        let Some(name) = &self.server_name else {
+            return Ok(self.authority.clone());
+        };
+        let host = match self.authority.port() {
+            Some(port) => format!("{}:{port}", name.to_str()),
+            None => name.to_str().into_owned(),
+        };
+        Authority::from_str(&host).map_err(|e| e.to_string())
+    }
+}
+
+fn parse_origin(s: &str) -> Result<Origin, String> {
+    let (host, name) = match s.split_once('=') {
+        Some((host, name)) => (host, Some(name)),
+        None => (s, None),
+    };
+    let origin = Origin {
+        authority: Authority::from_str(host).map_err(|e| e.to_string())?,
+        server_name: name
+            .map(|n| ServerName::try_from(n.to_string()))
+            .transpose()
+            .map_err(|e| e.to_string())?,
+    };
+    origin.host()?;
+    Ok(origin)
+}
+
+/// Upstream host and its mirrors, the host itself is the first one.
+#[derive(Debug, Clone)]
+struct Server {
+    authority: Authority,
+    mirrors: Vec<Origin>,
+}
+
+fn parse_server(s: &str) -> Result<Server, String> {
+    let mirrors = s
+        .split(',')
+        .map(parse_origin)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Server {
+        authority: mirrors[0].authority.clone(),
+        mirrors,
+    })
+}
+impl Server {
+    fn mirrors(&self) -> Result<Mirrors, NoMirrors> {
+        Mirrors::with_hosts(
+            self.mirrors
+                .iter()
+                // host is checked when parsed
+                .map(|m| (m.authority.clone(), m.host().unwrap()))
+                .collect(),
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 enum Listen {
     Unix(String),
@@ -38,13 +194,283 @@ enum Listen {
 }
 
 #[derive(Debug, clap::Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    #[arg(long, default_value_t)]
+    #[arg(long, default_value_t, global = true)]
     log_output: LogOutput,
+    #[command(subcommand)]
+    command: Option<Command>,
     #[command(flatten)]
-    listen: Listen,
+    serve: Option<ServeArgs>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Fetch paths into cache, so that they can be served offline
+    Prefetch(Box<PrefetchArgs>),
+    /// Write cached responses to an archive
+    Export(ExportArgs),
+    /// Read cached responses from an archive
+    Import(ImportArgs),
+    /// Rewrite cached responses of all servers in the current format and compact their
+    /// index, the proxy must not be running
+    Migrate(MigrateArgs),
+}
+
+/// Normalization of cache keys.
+#[derive(Debug, clap::Args)]
+// keep doc comment out of help of commands it is flattened into
+#[command(about = None, long_about = None)]
+struct KeyArgs {
+    /// Sort query parameters in cache keys
+    #[arg(long)]
+    sort_query: bool,
+    /// Drop query parameters with name matching the glob from cache keys, e.g. `utm_*`
+    #[arg(long = "drop-param", value_name = "NAME")]
+    drop_params: Vec<String>,
+    /// Collapse consecutive slashes of path in cache keys
+    #[arg(long)]
+    collapse_slashes: bool,
+    /// Decode percent-encoded unreserved characters in cache keys
+    #[arg(long)]
+    decode_unreserved: bool,
+    /// Lowercase path in cache keys
+    #[arg(long)]
+    lowercase_path: bool,
+}
+impl From<KeyArgs> for KeyNormalization {
+    fn from(value: KeyArgs) -> Self {
+        Self {
+            sort_query: value.sort_query,
+            drop_params: value.drop_params,
+            collapse_slashes: value.collapse_slashes,
+            decode_unreserved: value.decode_unreserved,
+            lowercase_path: value.lowercase_path,
+        }
+    }
+}
+
+/// Forward proxy connections to upstream are tunneled through.
+#[derive(Debug, clap::Args)]
+#[command(about = None, long_about = None)]
+struct EgressArgs {
+    /// Connect to upstream through proxy, as `http://[user:password@]host:port` for HTTP CONNECT,
+    /// `socks5://[user:password@]host:port`, or `socks5h://` to resolve host names by the proxy
+    #[arg(long, value_parser = Egress::parse)]
+    egress_proxy: Option<Egress>,
+    /// File containing `user:password` to authenticate with egress proxy
+    #[arg(long, requires = "egress_proxy")]
+    egress_proxy_auth: Option<PathBuf>,
+}
+impl EgressArgs {
+    fn egress(self) -> anyhow::Result<Egress> {
+        let egress = self.egress_proxy.unwrap_or_default();
+        Ok(match self.egress_proxy_auth {
+            Some(path) => {
+                let auth = std::fs::read_to_string(&path).with_context(|| {
+                    format!("failed to read egress proxy credentials {}", path.display())
+                })?;
+                let (username, password) = auth
+                    .trim_end_matches(['\r', '\n'])
+                    .split_once(':')
+                    .context("egress proxy credentials must be `user:password`")?;
+                egress.with_auth(ProxyAuth {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                })
+            }
+            None => egress,
+        })
+    }
+}
+
+/// TLS options of upstream connections.
+#[derive(Debug, clap::Args)]
+#[command(about = None, long_about = None)]
+struct TlsArgs {
+    /// PEM bundle of additional trusted root certificates
+    #[arg(long = "ca-file", value_name = "PATH")]
+    ca_files: Vec<PathBuf>,
+    /// Don't trust root certificates of the platform, only those in `--ca-file`
+    #[arg(long, requires = "ca_files")]
+    no_native_roots: bool,
+    /// Pin public key of upstream, as `TLS server name=sha256//<base64 SPKI hash>`,
+    /// may be given multiple times for a server
+    #[arg(long = "spki-pin", value_name = "NAME=PIN")]
+    spki_pins: Vec<String>,
+    /// PEM file of client certificate chain presented to upstream
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<PathBuf>,
+    /// PEM file of private key of client certificate
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+}
+impl TlsArgs {
+    fn config(self) -> anyhow::Result<TlsConfig> {
+        let mut config = TlsConfig::new().native_roots(!self.no_native_roots);
+        for path in &self.ca_files {
+            config = config.add_roots(path)?;
+        }
+        for pin in &self.spki_pins {
+            let (name, pin) = pin
+                .split_once('=')
+                .with_context(|| format!("invalid spki pin {pin:?}, expect NAME=PIN"))?;
+            config = config.pin(name, pin)?;
+        }
+        if let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key) {
+            config = config.client_cert(cert, key)?;
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct PrefetchArgs {
+    /// Maximum number of paths fetched at the same time
+    #[arg(long, default_value_t = 8)]
+    concurrency: usize,
+    /// JSON file mapping URLs to their subresource integrity, bodies not matching are refused
+    #[arg(long)]
+    pins: Option<PathBuf>,
+    /// JSON file of rules overriding cache policy of paths
+    #[arg(long)]
+    rules: Option<PathBuf>,
+    /// JSON file of rules rewriting headers of requests sent to upstream
+    #[arg(long)]
+    upstream_headers: Option<PathBuf>,
+    #[command(flatten)]
+    key: KeyArgs,
+    #[command(flatten)]
+    egress: EgressArgs,
+    #[command(flatten)]
+    tls: TlsArgs,
+    root: String,
+    #[arg(value_parser = parse_server)]
+    server: Server,
+    /// File listing paths or URLs one per line, or a sitemap, read from stdin if it is `-` or not given
+    manifest: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct ExportArgs {
+    /// Only export entries with key starting with it
+    #[arg(long, default_value = "")]
+    prefix: String,
+    /// Only export entries that are still fresh
+    #[arg(long)]
+    fresh: bool,
     root: String,
     server: String,
+    archive: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+enum ExistingArg {
+    #[default]
+    Keep,
+    Replace,
+    Newer,
+}
+impl Display for ExistingArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Keep => "keep",
+            Self::Replace => "replace",
+            Self::Newer => "newer",
+        })
+    }
+}
+impl From<ExistingArg> for Existing {
+    fn from(value: ExistingArg) -> Self {
+        match value {
+            ExistingArg::Keep => Self::Keep,
+            ExistingArg::Replace => Self::Replace,
+            ExistingArg::Newer => Self::Newer,
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct ImportArgs {
+    /// How to handle entries that are already cached
+    #[arg(long, default_value_t)]
+    existing: ExistingArg,
+    root: String,
+    server: String,
+    archive: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+struct MigrateArgs {
+    root: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+// clap leaves the group empty when there are flattened fields, so name
+// the required arguments, or `Option<ServeArgs>` is never present
+#[group(args = ["root", "servers"])]
+struct ServeArgs {
+    #[command(flatten)]
+    listen: Listen,
+    /// Serve cached responses regardless of freshness, toggled by SIGUSR1
+    #[arg(long)]
+    offline: bool,
+    /// Maximum number of stale entries revalidated in background at the same time
+    #[arg(long, default_value_t = local_cdn_proxy::DEFAULT_MAX_REFRESH)]
+    max_refresh: usize,
+    /// Forward HEAD requests of uncached paths to upstream instead of filling the cache
+    #[arg(long)]
+    head_upstream: bool,
+    /// Maximum total size of cached content, e.g. 10G
+    #[arg(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// Remove entries not accessed for this long, e.g. 30d
+    #[arg(long, value_parser = parse_duration)]
+    max_age: Option<Duration>,
+    /// Entries evicted first when the cache exceeds maximum size
+    #[arg(long, default_value_t)]
+    eviction: EvictionArg,
+    /// Interval of garbage collection
+    #[arg(long, value_parser = parse_duration, default_value = "1h")]
+    gc_interval: Duration,
+    /// Unix socket of admin API
+    #[arg(long, conflicts_with = "admin_tcp")]
+    admin_unix: Option<String>,
+    /// TCP address of admin API, it must be a loopback address unless
+    /// `--admin-token-file` is given
+    #[arg(long)]
+    admin_tcp: Option<std::net::SocketAddr>,
+    /// File with the token admin API requests must send as bearer token
+    #[arg(long)]
+    admin_token_file: Option<PathBuf>,
+    /// Unix socket serving Prometheus metrics on `/metrics`
+    #[arg(long, conflicts_with = "metrics_tcp")]
+    metrics_unix: Option<String>,
+    /// TCP address serving Prometheus metrics on `/metrics`
+    #[arg(long)]
+    metrics_tcp: Option<std::net::SocketAddr>,
+    /// JSON file mapping URLs to their subresource integrity, bodies not matching are refused
+    #[arg(long)]
+    pins: Option<PathBuf>,
+    /// JSON file of rules overriding cache policy of paths
+    #[arg(long)]
+    rules: Option<PathBuf>,
+    /// JSON file of rules rewriting headers of requests sent to upstream
+    #[arg(long)]
+    upstream_headers: Option<PathBuf>,
+    #[command(flatten)]
+    key: KeyArgs,
+    #[command(flatten)]
+    egress: EgressArgs,
+    #[command(flatten)]
+    tls: TlsArgs,
+    root: String,
+    /// Upstream hosts, each as `host` or `host=TLS server name`, optionally
+    /// followed by mirrors in the same form separated by `,`, which are
+    /// tried in order if the host fails. The server name is also sent as
+    /// `Host` of requests
+    #[arg(required = true, value_parser = parse_server)]
+    servers: Vec<Server>,
 }
 
 impl Args for Listen {
@@ -68,7 +494,7 @@ impl FromArgMatches for Listen {
             matches.get_one::<std::net::SocketAddr>("tcp"),
         ) {
             (Some(u), None) => Ok(Self::Unix(u.clone())),
-            (None, Some(t)) => Ok(Self::Tcp(t.clone())),
+            (None, Some(t)) => Ok(Self::Tcp(*t)),
             _ => unreachable!(),
         }
     }
@@ -78,7 +504,7 @@ impl FromArgMatches for Listen {
             matches.get_one::<std::net::SocketAddr>("tcp"),
         ) {
             (Some(u), None) => *self = Self::Unix(u.clone()),
-            (None, Some(t)) => *self = Self::Tcp(t.clone()),
+            (None, Some(t)) => *self = Self::Tcp(*t),
             (None, None) => (),
             _ => unreachable!(),
         }
@@ -114,6 +540,75 @@ async fn serve_connection<S, B, I>(
     }
 }
 
+/// Accept connections on `listen` and serve them with `service`.
+///
+/// Unix socket is created with permission `mode`.
+async fn serve<S, B>(
+    listen: Listen,
+    mode: u32,
+    builder: hyper_util::server::conn::auto::Builder<TokioExecutor>,
+    service: S,
+) -> anyhow::Result<()>
+where
+    S: Clone + Send + 'static,
+    S: tower_service::Service<Request<Incoming>, Response = http::Response<B>>,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    S::Future: Send,
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    match listen {
+        Listen::Tcp(s) => {
+            let listener = tokio::net::TcpListener::bind(s)
+                .await
+                .with_context(|| format!("failed to bind to tcp addr {s}"))?;
+            tracing::info!(addr = %s, "listening tcp connection");
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        tokio::spawn(
+                            serve_connection(
+                                builder.clone(),
+                                service.clone(),
+                                TokioIo::new(stream),
+                            )
+                            .instrument(tracing::info_span!("tcp_client", addr = %addr)),
+                        );
+                    }
+                    Err(e) => {
+                        tracing::error!("failed to get client {:?}", anyhow::Error::new(e))
+                    }
+                }
+            }
+        }
+        Listen::Unix(u) => {
+            let listener = tokio::net::UnixListener::bind(u.as_str())
+                .with_context(|| format!("failed to bind to unix socket: {u}"))?;
+            tracing::info!(addr = u, "listening unix socket");
+            std::fs::set_permissions(&u, Permissions::from_mode(mode))
+                .context("failed to set socket permission")?;
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        tokio::spawn(
+                            serve_connection(
+                                builder.clone(),
+                                service.clone(),
+                                TokioIo::new(stream),
+                            )
+                            .instrument(tracing::info_span!("unix_client", addr = ?addr)),
+                        );
+                    }
+                    Err(e) => {
+                        tracing::error!("failed to get client {:?}", anyhow::Error::new(e))
+                    }
+                }
+            }
+        }
+    }
+}
+
 fn map_result<E: std::error::Error + Send + Sync + 'static>(
     r: Result<CachedResponse, ProxyError<E>>,
 ) -> Result<CachedResponse, ProxyError<E>> {
@@ -127,7 +622,7 @@ fn map_result<E: std::error::Error + Send + Sync + 'static>(
                 header::CONTENT_TYPE,
                 header::HeaderValue::from_static("text/plain"),
             )
-            .body(Either::Right(Full::new(Bytes::from(
+            .body(CachedBody::Full(Full::new(Bytes::from(
                 format!("{:?}", anyhow::Error::new(err)).into_bytes(),
             ))))
             .unwrap()
@@ -139,13 +634,16 @@ fn map_result<E: std::error::Error + Send + Sync + 'static>(
             match &e {
                 ProxyError::MissingHost
                 | ProxyError::InvalidHost(_, _)
-                | ProxyError::UnexpectedHost(_)
                 | ProxyError::InvalidUri(_)
                 | ProxyError::InvalidPath(_, _) => Ok(error_response(StatusCode::BAD_REQUEST, e)),
-                ProxyError::Upstream(_) | ProxyError::BoxedUpstream(_) => {
-                    Ok(error_response(StatusCode::BAD_GATEWAY, e))
+                ProxyError::UnknownHost(_) => {
+                    Ok(error_response(StatusCode::MISDIRECTED_REQUEST, e))
                 }
-                ProxyError::ReadCache(_) | ProxyError::Decode(_) | ProxyError::WriteCache(_) => {
+                ProxyError::Upstream(_)
+                | ProxyError::BoxedUpstream(_)
+                | ProxyError::Integrity(_) => Ok(error_response(StatusCode::BAD_GATEWAY, e)),
+                ProxyError::Offline(_) => Ok(error_response(StatusCode::GATEWAY_TIMEOUT, e)),
+                ProxyError::ReadCache(_) | ProxyError::WriteCache(_) => {
                     Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, e))
                 }
             }
@@ -153,23 +651,164 @@ fn map_result<E: std::error::Error + Send + Sync + 'static>(
     }
 }
 
-fn run(root: PathBuf, server: String, listen: Listen) -> anyhow::Result<()> {
+type Client = hyper_util::client::legacy::Client<
+    local_cdn_proxy::connector::Connector<
+        Tunnel<hyper_util::client::legacy::connect::HttpConnector>,
+    >,
+    local_cdn_proxy::UpstreamBody,
+>;
+
+fn client(servers: &[Server], egress: Egress, tls: TlsConfig) -> anyhow::Result<Client> {
+    let mut http = hyper_util::client::legacy::connect::HttpConnector::new();
+    // scheme is checked by https connector
+    http.enforce_http(false);
+    let mut names = ServerNames::default();
+    let mut seen = HashMap::new();
+    for m in servers.iter().flat_map(|s| &s.mirrors) {
+        // the same origin is connected with one server name
+        match seen.insert(&m.authority, &m.server_name) {
+            Some(name) if name != &m.server_name => {
+                anyhow::bail!("conflicting TLS server names of {}", m.authority)
+            }
+            _ => {}
+        }
+        if let Some(name) = &m.server_name {
+            names.insert(&m.authority, name.clone());
+        }
+    }
+    let tls = tls.client_config().context("failed to create tls config")?;
+    Ok(
+        hyper_util::client::legacy::Builder::new(hyper_util::rt::TokioExecutor::new())
+            .build(Connector::new(Tunnel::new(http, egress), tls, names)),
+    )
+}
+
+/// Pinned integrity of `servers` in manifest `path`, by authority.
+fn load_pins(path: Option<&Path>, servers: &[Server]) -> anyhow::Result<HashMap<Authority, Pins>> {
+    let path = match path {
+        Some(p) => p,
+        None => return Ok(HashMap::new()),
+    };
+    let manifest = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read integrity manifest {}", path.display()))?;
+    let pins = local_cdn_proxy::pin::parse_manifest(&manifest)?;
+    for (authority, p) in &pins {
+        if servers.iter().any(|s| &s.authority == authority) {
+            tracing::info!(host = %authority, paths = p.len(), "loaded pinned integrity");
+        } else {
+            tracing::warn!(host = %authority, "pinned host is not proxied, ignored");
+        }
+    }
+    Ok(pins)
+}
+
+fn load_rules(path: Option<&Path>) -> anyhow::Result<Rules> {
+    match path {
+        Some(p) => {
+            let rules = std::fs::read_to_string(p)
+                .with_context(|| format!("failed to read cache rules {}", p.display()))?;
+            Ok(Rules::parse(&rules)?)
+        }
+        None => Ok(Rules::default()),
+    }
+}
+
+fn load_upstream_headers(path: Option<&Path>) -> anyhow::Result<HeaderRewrite> {
+    match path {
+        Some(p) => {
+            let rules = std::fs::read_to_string(p)
+                .with_context(|| format!("failed to read header rules {}", p.display()))?;
+            Ok(HeaderRewrite::parse(&rules)?)
+        }
+        None => Ok(HeaderRewrite::default()),
+    }
+}
+
+fn load_admin_token(path: Option<&Path>) -> anyhow::Result<Option<String>> {
+    let Some(p) = path else {
+        return Ok(None);
+    };
+    let token = std::fs::read_to_string(p)
+        .with_context(|| format!("failed to read admin token {}", p.display()))?;
+    let token = token.trim();
+    if token.is_empty() {
+        anyhow::bail!("admin token {} is empty", p.display());
+    }
+    Ok(Some(token.to_string()))
+}
+
+fn run(cli: ServeArgs) -> anyhow::Result<()> {
+    let admin_token = load_admin_token(cli.admin_token_file.as_deref())?;
+    if let Some(addr) = cli.admin_tcp.filter(|a| !a.ip().is_loopback()) {
+        if admin_token.is_none() {
+            anyhow::bail!("admin api on non-loopback address {addr} requires --admin-token-file");
+        }
+    }
     let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    let root = PathBuf::from(cli.root);
+    let offline = OfflineMode::new(cli.offline);
+    rt.spawn({
+        let offline = offline.clone();
+        async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut sig = match signal(SignalKind::user_defined1()) {
+                Ok(s) => s,
+                Err(e) => {
+                    tracing::error!("failed to listen SIGUSR1: {:?}", anyhow::Error::new(e));
+                    return;
+                }
+            };
+            while sig.recv().await.is_some() {
+                let enabled = offline.toggle();
+                tracing::info!(enabled, "toggled offline mode");
+            }
+        }
+    });
+
+    let gc_config = GcConfig {
+        max_size: cli.max_size,
+        max_age: cli.max_age,
+        eviction: cli.eviction.into(),
+    };
+    let mut pins = load_pins(cli.pins.as_deref(), &cli.servers)?;
+    let rules = load_rules(cli.rules.as_deref())?;
+    let headers = load_upstream_headers(cli.upstream_headers.as_deref())?;
+    let key = KeyNormalization::from(cli.key);
+    let metrics = Metrics::new();
+    let mut layers = Vec::new();
+    for server in &cli.servers {
+        let root = host_root(&root, &server.authority);
+        std::fs::create_dir_all(&root)
+            .with_context(|| format!("failed to create cache root {}", root.display()))?;
+        let access = AccessLog::load(&root);
+        rt.spawn(
+            local_cdn_proxy::gc::run(
+                root.clone(),
+                access.clone(),
+                gc_config.clone(),
+                cli.gc_interval,
+            )
+            .instrument(tracing::info_span!("gc", host = %server.authority)),
+        );
+        layers.push(
+            local_cdn_proxy::CacheLayer::new(root, server.authority.clone())
+                .offline(offline.clone())
+                .max_refresh(cli.max_refresh)
+                .access_log(access)
+                .pins(pins.remove(&server.authority).unwrap_or_default())
+                .rules(rules.for_host(&server.authority))
+                .key_normalization(key.clone())
+                .upstream_headers(headers.clone())
+                .mirrors(server.mirrors()?)
+                .head_upstream(cli.head_upstream)
+                .metrics(metrics.clone()),
+        );
+    }
+    let hosts = HostLayer::new(layers);
 
-    let authority = Authority::from_str(&server).context("invalid server name")?;
-
-    let client = hyper_util::client::legacy::Builder::new(hyper_util::rt::TokioExecutor::new())
-        .build::<_, local_cdn_proxy::UpstreamBody>(local_cdn_proxy::connector::Connector(
-        hyper_rustls::HttpsConnectorBuilder::new()
-            .with_native_roots()
-            .context("failed to get certificate roots")?
-            .https_only()
-            .with_server_name_resolver(hyper_rustls::FixedServerNameResolver::new(
-                server.try_into().context("invalid server name")?,
-            ))
-            .enable_all_versions()
-            .build(),
-    ));
+    let client = client(&cli.servers, cli.egress.egress()?, cli.tls.config()?)?;
+    let admin = local_cdn_proxy::admin::Admin::new(hosts.layer(client.clone()))
+        .token(admin_token.as_deref());
     let service = tower::ServiceBuilder::new()
         .layer(
             tower_http::trace::TraceLayer::new_for_http()
@@ -181,62 +820,236 @@ fn run(root: PathBuf, server: String, listen: Listen) -> anyhow::Result<()> {
                     tower_http::trace::DefaultOnResponse::new().level(tracing::Level::INFO),
                 ),
         )
-        .map_result(map_result)
-        .layer(local_cdn_proxy::CacheLayer::new(root, authority))
+        .map_result({
+            let metrics = metrics.clone();
+            move |r| {
+                if let Err(e) = &r {
+                    metrics.error(e);
+                }
+                map_result(r)
+            }
+        })
+        .layer(hosts)
         .service(client);
 
     let builder =
         hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
 
-    match listen {
-        Listen::Tcp(s) => rt.block_on(async move {
-            let listener = tokio::net::TcpListener::bind(s)
-                .await
-                .with_context(|| format!("failed to bind to tcp addr {s}"))?;
-            tracing::info!(addr = %s, "listening tcp connection");
-            loop {
-                match listener.accept().await {
-                    Ok((stream, addr)) => {
-                        tokio::spawn(
-                            serve_connection(
-                                builder.clone(),
-                                service.clone(),
-                                TokioIo::new(stream),
-                            )
-                            .instrument(tracing::info_span!("tcp_client", addr = %addr)),
-                        );
+    let admin_listen = match (cli.admin_unix, cli.admin_tcp) {
+        (Some(u), _) => Some(Listen::Unix(u)),
+        (None, Some(t)) => Some(Listen::Tcp(t)),
+        (None, None) => None,
+    };
+    let metrics_listen = match (cli.metrics_unix, cli.metrics_tcp) {
+        (Some(u), _) => Some(Listen::Unix(u)),
+        (None, Some(t)) => Some(Listen::Tcp(t)),
+        (None, None) => None,
+    };
+    rt.block_on(async move {
+        if let Some(listen) = admin_listen {
+            let builder = builder.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(e) = serve(listen, 0o600, builder, admin).await {
+                        tracing::error!("failed to serve admin api: {e:?}");
                     }
-                    Err(e) => {
-                        tracing::error!("failed to get client {:?}", anyhow::Error::new(e))
+                }
+                .instrument(tracing::info_span!("admin")),
+            );
+        }
+        if let Some(listen) = metrics_listen {
+            let builder = builder.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(e) = serve(listen, 0o666, builder, metrics).await {
+                        tracing::error!("failed to serve metrics: {e:?}");
                     }
                 }
+                .instrument(tracing::info_span!("metrics")),
+            );
+        }
+        serve(cli.listen, 0o666, builder, service).await
+    })
+}
+
+/// Paths to prefetch listed in `manifest`, URLs must be of `authority`.
+///
+/// Manifest is either a sitemap, or a list of paths or URLs one per line,
+/// empty lines and lines starting with `#` are ignored.
+fn parse_manifest(manifest: &str, authority: &Authority) -> anyhow::Result<Vec<String>> {
+    let items: Vec<String> = if manifest.trim_start().starts_with('<') {
+        manifest
+            .split("<loc>")
+            .skip(1)
+            .filter_map(|s| s.split_once("</loc>"))
+            .map(|(loc, _)| {
+                loc.trim()
+                    .replace("&lt;", "<")
+                    .replace("&gt;", ">")
+                    .replace("&quot;", "\"")
+                    .replace("&apos;", "'")
+                    .replace("&amp;", "&")
+            })
+            .collect()
+    } else {
+        manifest
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(str::to_string)
+            .collect()
+    };
+    let mut paths = Vec::new();
+    for item in items {
+        let path = if item.starts_with('/') {
+            item
+        } else {
+            let uri = Uri::from_str(&item).with_context(|| format!("invalid url {item}"))?;
+            if uri.authority() != Some(authority) {
+                anyhow::bail!("url {item} is not of server {authority}");
             }
-        }),
-        Listen::Unix(u) => rt.block_on(async {
-            let listener = tokio::net::UnixListener::bind(u.as_str())
-                .with_context(|| format!("failed to bind to unix socket: {u}"))?;
-            tracing::info!(addr = u, "listening unix socket");
-            std::fs::set_permissions(u, Permissions::from_mode(0o666))
-                .context("failed to set socket permission")?;
-            loop {
-                match listener.accept().await {
-                    Ok((stream, addr)) => {
-                        tokio::spawn(
-                            serve_connection(
-                                builder.clone(),
-                                service.clone(),
-                                TokioIo::new(stream),
-                            )
-                            .instrument(tracing::info_span!("unix_client", addr = ?addr)),
-                        );
+            uri.path_and_query().map_or("/", |p| p.as_str()).to_string()
+        };
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+fn prefetch(args: PrefetchArgs) -> anyhow::Result<()> {
+    let rt = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
+    let manifest = match args.manifest.as_deref() {
+        None | Some("-") => std::io::read_to_string(std::io::stdin()),
+        Some(p) => std::fs::read_to_string(p),
+    }
+    .context("failed to read manifest")?;
+    let authority = args.server.authority.clone();
+    let paths = parse_manifest(&manifest, &authority)?;
+    let total = paths.len();
+    let pins = load_pins(args.pins.as_deref(), std::slice::from_ref(&args.server))?
+        .remove(&authority)
+        .unwrap_or_default();
+    let rules = load_rules(args.rules.as_deref())?.for_host(&authority);
+    let proxy = local_cdn_proxy::CacheLayer::new(
+        host_root(&PathBuf::from(args.root), &authority),
+        authority,
+    )
+    .pins(pins)
+    .rules(rules)
+    .key_normalization(args.key.into())
+    .upstream_headers(load_upstream_headers(args.upstream_headers.as_deref())?)
+    .mirrors(args.server.mirrors()?)
+    .layer(client(
+        std::slice::from_ref(&args.server),
+        args.egress.egress()?,
+        args.tls.config()?,
+    )?);
+
+    let failed = rt.block_on(
+        futures_util::stream::iter(paths)
+            .map(|path| {
+                let mut proxy = proxy.clone();
+                async move {
+                    let result = proxy
+                        .prefetch(&path)
+                        .instrument(tracing::info_span!("prefetch", path))
+                        .await;
+                    (path, result)
+                }
+            })
+            .buffer_unordered(args.concurrency.max(1))
+            .fold(0, |failed, (path, result)| async move {
+                match result {
+                    Ok(r) if r.is_success() => {
+                        println!("ok\t{path}\t{r}");
+                        failed
+                    }
+                    Ok(r) => {
+                        println!("failed\t{path}\t{r}");
+                        failed + 1
                     }
                     Err(e) => {
-                        tracing::error!("failed to get client {:?}", anyhow::Error::new(e))
+                        println!("failed\t{path}\t{e}");
+                        failed + 1
                     }
                 }
-            }
-        }),
+            }),
+    );
+    tracing::info!(total, failed, "prefetch finished");
+    if failed > 0 {
+        anyhow::bail!("failed to prefetch {failed} of {total} paths");
     }
+    Ok(())
+}
+
+fn export(args: ExportArgs) -> anyhow::Result<()> {
+    let authority = Authority::from_str(&args.server).context("invalid server name")?;
+    let file = std::fs::File::create(&args.archive)
+        .with_context(|| format!("failed to create archive {}", args.archive.display()))?;
+    let stats = archive::export(
+        &host_root(&PathBuf::from(args.root), &authority),
+        &authority,
+        &ExportFilter {
+            prefix: args.prefix,
+            fresh: args.fresh,
+        },
+        std::io::BufWriter::new(file),
+    )?;
+    tracing::info!(
+        entries = stats.entries,
+        skipped = stats.skipped,
+        "export finished"
+    );
+    Ok(())
+}
+
+fn import(args: ImportArgs) -> anyhow::Result<()> {
+    let authority = Authority::from_str(&args.server).context("invalid server name")?;
+    let file = std::fs::File::open(&args.archive)
+        .with_context(|| format!("failed to open archive {}", args.archive.display()))?;
+    let stats = archive::import(
+        &host_root(&PathBuf::from(args.root), &authority),
+        &authority,
+        args.existing.into(),
+        std::io::BufReader::new(file),
+    )?;
+    tracing::info!(
+        entries = stats.entries,
+        skipped = stats.skipped,
+        "import finished"
+    );
+    Ok(())
+}
+
+fn migrate(args: MigrateArgs) -> anyhow::Result<()> {
+    let dirs = std::fs::read_dir(&args.root)
+        .with_context(|| format!("failed to read cache root {}", args.root.display()))?;
+    for dir in dirs {
+        let dir = dir.context("failed to read cache root")?.path();
+        if !dir.join("index-v5").is_dir() {
+            continue;
+        }
+        let stats = migrate::migrate(&dir)
+            .with_context(|| format!("failed to migrate {}", dir.display()))?;
+        tracing::info!(
+            root = %dir.display(),
+            current = stats.current,
+            upgraded = stats.upgraded,
+            evicted = stats.evicted,
+            index_reclaimed = stats.index_reclaimed,
+            "migration finished"
+        );
+        // remove content of entries that are rewritten
+        let gc = local_cdn_proxy::gc::collect(&dir, &AccessLog::load(&dir), &GcConfig::default())
+            .with_context(|| format!("failed to collect garbage in {}", dir.display()))?;
+        tracing::info!(
+            blobs = gc.blobs,
+            reclaimed = gc.reclaimed,
+            "old content removed"
+        );
+    }
+    Ok(())
 }
 
 fn main() -> ExitCode {
@@ -274,7 +1087,16 @@ fn main() -> ExitCode {
             .init(),
     }
 
-    match run(cli.root.into(), cli.server, cli.listen) {
+    let result = match (cli.command, cli.serve) {
+        (Some(Command::Prefetch(args)), _) => prefetch(*args),
+        (Some(Command::Export(args)), _) => export(args),
+        (Some(Command::Import(args)), _) => import(args),
+        (Some(Command::Migrate(args)), _) => migrate(args),
+        (None, Some(args)) => run(args),
+        // clap requires arguments of server without subcommand
+        (None, None) => unreachable!(),
+    };
+    match result {
         Ok(()) => ExitCode::SUCCESS,
         Err(e) => {
             tracing::error!("error: {e:?}");
@@ -282,3 +1104,59 @@ fn main() -> ExitCode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authorities(server: &Server) -> Vec<&str> {
+        server
+            .mirrors
+            .iter()
+            .map(|m| m.authority.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn parse_single_server() {
+        let server = parse_server("cdn.example.com").unwrap();
+        assert_eq!(server.authority, "cdn.example.com");
+        assert_eq!(authorities(&server), ["cdn.example.com"]);
+        assert!(server.mirrors[0].server_name.is_none());
+    }
+
+    #[test]
+    fn parse_server_with_mirrors() {
+        let server =
+            parse_server("cdn.example.com,mirror.example.net:8443,10.0.0.1=cdn.example.com")
+                .unwrap();
+        assert_eq!(server.authority, "cdn.example.com");
+        assert_eq!(
+            authorities(&server),
+            ["cdn.example.com", "mirror.example.net:8443", "10.0.0.1"]
+        );
+        assert_eq!(
+            server.mirrors[2].server_name,
+            Some(ServerName::try_from("cdn.example.com").unwrap())
+        );
+        assert_eq!(server.mirrors[1].host().unwrap(), "mirror.example.net:8443");
+        assert_eq!(server.mirrors[2].host().unwrap(), "cdn.example.com");
+        let server = parse_server("cdn.example.com,10.0.0.1:8443=cdn.example.com").unwrap();
+        assert_eq!(server.mirrors[1].host().unwrap(), "cdn.example.com:8443");
+    }
+
+    #[test]
+    fn parse_invalid_server() {
+        assert!(parse_server("").is_err());
+        assert!(parse_server("cdn.example.com,").is_err());
+        assert!(parse_server("cdn example.com").is_err());
+        assert!(parse_server("cdn.example.com=").is_err());
+        assert!(parse_server("cdn.example.com,10.0.0.1=in valid").is_err());
+    }
+
+    #[test]
+    fn cli() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+}
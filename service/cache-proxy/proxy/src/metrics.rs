@@ -0,0 +1,385 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use futures_util::future::{ready, Ready};
+use http::{header, Method, Request, Response, StatusCode};
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use prometheus_client::{
+    encoding::EncodeLabelSet,
+    metrics::{
+        counter::Counter,
+        family::Family,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+use tower_http::trace::OnBodyChunk;
+use tower_service::Service;
+
+use crate::ProxyError;
+
+/// How a request is answered after looking up the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Lookup {
+    /// Fresh response is served from cache.
+    Hit,
+    /// Response is not cached and fetched from upstream.
+    Miss,
+    /// Cached response is stale and revalidated before it is served.
+    Expired,
+    /// Stale response is served while it is revalidated in background.
+    Stale,
+    /// Cached response is served in offline mode.
+    Offline,
+    /// Cached response can't be used and the request is forwarded.
+    Uncacheable,
+}
+impl Lookup {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Hit => "hit",
+            Self::Miss => "miss",
+            Self::Expired => "expired",
+            Self::Stale => "stale",
+            Self::Offline => "offline",
+            Self::Uncacheable => "uncacheable",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Revalidation {
+    Modified,
+    NotModified,
+    /// Upstream can't be reached and stale response is used.
+    Failed,
+}
+impl Revalidation {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Modified => "modified",
+            Self::NotModified => "not_modified",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// Why a request is sent to upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Upstream {
+    /// Client request is forwarded without caching.
+    Forward,
+    /// Cache entry is filled or revalidated.
+    Fill,
+}
+impl Upstream {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Forward => "forward",
+            Self::Fill => "fill",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct HostLabels {
+    host: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct LookupLabels {
+    host: String,
+    result: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RevalidationLabels {
+    host: String,
+    result: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct UpstreamLabels {
+    host: String,
+    kind: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ErrorLabels {
+    error: &'static str,
+}
+
+fn latency_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.005, 2.0, 14))
+}
+
+struct Inner {
+    registry: Registry,
+    lookups: Family<LookupLabels, Counter>,
+    revalidations: Family<RevalidationLabels, Counter>,
+    forwarded: Family<HostLabels, Counter>,
+    errors: Family<ErrorLabels, Counter>,
+    cache_bytes: Family<HostLabels, Counter>,
+    upstream_bytes: Family<UpstreamLabels, Counter>,
+    upstream_latency: Family<UpstreamLabels, Histogram, fn() -> Histogram>,
+}
+
+/// Metrics of cache proxies, served in Prometheus text format.
+#[derive(Clone)]
+pub struct Metrics(Arc<Inner>);
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Metrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("local_cdn_proxy");
+        let lookups = Family::default();
+        registry.register(
+            "lookups",
+            "Requests looked up in cache, by how they are answered",
+            lookups.clone(),
+        );
+        let revalidations = Family::default();
+        registry.register(
+            "revalidations",
+            "Revalidations of stale cached responses with upstream",
+            revalidations.clone(),
+        );
+        let forwarded = Family::default();
+        registry.register(
+            "forwarded_requests",
+            "Requests forwarded to upstream without caching",
+            forwarded.clone(),
+        );
+        let errors = Family::default();
+        registry.register("errors", "Requests failed with error", errors.clone());
+        let cache_bytes = Family::default();
+        registry.register(
+            "cache_sent_bytes",
+            "Bytes of response bodies served from cache",
+            cache_bytes.clone(),
+        );
+        let upstream_bytes = Family::default();
+        registry.register(
+            "upstream_received_bytes",
+            "Bytes of response bodies received from upstream",
+            upstream_bytes.clone(),
+        );
+        let upstream_latency =
+            Family::<_, _, fn() -> Histogram>::new_with_constructor(latency_histogram);
+        registry.register(
+            "upstream_latency_seconds",
+            "Time until response headers are received from upstream",
+            upstream_latency.clone(),
+        );
+        Self(Arc::new(Inner {
+            registry,
+            lookups,
+            revalidations,
+            forwarded,
+            errors,
+            cache_bytes,
+            upstream_bytes,
+            upstream_latency,
+        }))
+    }
+    /// Metrics in Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buf = String::new();
+        prometheus_client::encoding::text::encode(&mut buf, &self.0.registry).unwrap();
+        buf
+    }
+    /// Count error returned by a proxy.
+    pub fn error<E>(&self, err: &ProxyError<E>) {
+        let error = match err {
+            ProxyError::MissingHost => "missing_host",
+            ProxyError::InvalidHost(_, _) => "invalid_host",
+            ProxyError::UnknownHost(_) => "unknown_host",
+            ProxyError::InvalidUri(_) => "invalid_uri",
+            ProxyError::InvalidPath(_, _) => "invalid_path",
+            ProxyError::Upstream(_) => "upstream",
+            ProxyError::BoxedUpstream(_) => "boxed_upstream",
+            ProxyError::ReadCache(_) => "read_cache",
+            ProxyError::WriteCache(_) => "write_cache",
+            ProxyError::Integrity(_) => "integrity",
+            ProxyError::Offline(_) => "offline",
+        };
+        self.0.errors.get_or_create(&ErrorLabels { error }).inc();
+    }
+    pub(crate) fn host(&self, host: &str) -> HostMetrics {
+        HostMetrics {
+            metrics: self.clone(),
+            host: host.to_string(),
+        }
+    }
+}
+
+/// Metrics recorded by proxy of one host.
+#[derive(Clone)]
+pub(crate) struct HostMetrics {
+    metrics: Metrics,
+    host: String,
+}
+impl HostMetrics {
+    fn host_labels(&self) -> HostLabels {
+        HostLabels {
+            host: self.host.clone(),
+        }
+    }
+    pub(crate) fn lookup(&self, result: Lookup) {
+        let host = self.host.clone();
+        self.metrics
+            .0
+            .lookups
+            .get_or_create(&LookupLabels {
+                host,
+                result: result.as_str(),
+            })
+            .inc();
+    }
+    pub(crate) fn revalidation(&self, result: Revalidation) {
+        let host = self.host.clone();
+        self.metrics
+            .0
+            .revalidations
+            .get_or_create(&RevalidationLabels {
+                host,
+                result: result.as_str(),
+            })
+            .inc();
+    }
+    pub(crate) fn forwarded(&self) {
+        self.metrics
+            .0
+            .forwarded
+            .get_or_create(&self.host_labels())
+            .inc();
+    }
+    pub(crate) fn cache_sent(&self, bytes: usize) {
+        self.metrics
+            .0
+            .cache_bytes
+            .get_or_create(&self.host_labels())
+            .inc_by(bytes as u64);
+    }
+    pub(crate) fn upstream_latency(&self, kind: Upstream, latency: Duration) {
+        let host = self.host.clone();
+        self.metrics
+            .0
+            .upstream_latency
+            .get_or_create(&UpstreamLabels {
+                host,
+                kind: kind.as_str(),
+            })
+            .observe(latency.as_secs_f64());
+    }
+    pub(crate) fn count_bytes(&self, kind: Upstream) -> CountBytes {
+        CountBytes {
+            metrics: self.clone(),
+            kind,
+        }
+    }
+}
+
+/// Count bytes of upstream response bodies.
+#[derive(Clone)]
+pub struct CountBytes {
+    metrics: HostMetrics,
+    kind: Upstream,
+}
+impl OnBodyChunk<Bytes> for CountBytes {
+    fn on_body_chunk(&mut self, chunk: &Bytes, _: Duration, _: &tracing::Span) {
+        let host = self.metrics.host.clone();
+        self.metrics
+            .metrics
+            .0
+            .upstream_bytes
+            .get_or_create(&UpstreamLabels {
+                host,
+                kind: self.kind.as_str(),
+            })
+            .inc_by(chunk.len() as u64);
+    }
+}
+
+/// Serves `GET /metrics`.
+impl Service<Request<Incoming>> for Metrics {
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Infallible>>;
+    fn poll_ready(
+        &mut self,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        let resp = if req.method() == Method::GET && req.uri().path() == "/metrics" {
+            Response::builder()
+                .header(
+                    header::CONTENT_TYPE,
+                    "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                )
+                .body(Full::new(Bytes::from(self.encode())))
+        } else {
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Full::default())
+        };
+        ready(Ok(resp.unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::*;
+
+    #[test]
+    fn encode_counts() {
+        let metrics = Metrics::new();
+        let host = metrics.host("example.com");
+        host.lookup(Lookup::Hit);
+        host.lookup(Lookup::Miss);
+        host.revalidation(Revalidation::Modified);
+        host.revalidation(Revalidation::NotModified);
+        host.revalidation(Revalidation::NotModified);
+        host.forwarded();
+        host.cache_sent(100);
+        host.cache_sent(20);
+        let mut count = host.count_bytes(Upstream::Fill);
+        count.on_body_chunk(
+            &Bytes::from_static(b"abc"),
+            Duration::ZERO,
+            &tracing::Span::none(),
+        );
+        host.upstream_latency(Upstream::Fill, Duration::from_millis(30));
+        host.upstream_latency(Upstream::Fill, Duration::from_millis(3));
+        metrics.error(&ProxyError::<Infallible>::MissingHost);
+        let text = metrics.encode();
+        let lines: Vec<_> = text.lines().collect();
+        for line in [
+            r#"local_cdn_proxy_lookups_total{host="example.com",result="hit"} 1"#,
+            r#"local_cdn_proxy_lookups_total{host="example.com",result="miss"} 1"#,
+            r#"local_cdn_proxy_errors_total{error="missing_host"} 1"#,
+            r#"local_cdn_proxy_revalidations_total{host="example.com",result="modified"} 1"#,
+            r#"local_cdn_proxy_revalidations_total{host="example.com",result="not_modified"} 2"#,
+            r#"local_cdn_proxy_forwarded_requests_total{host="example.com"} 1"#,
+            r#"local_cdn_proxy_cache_sent_bytes_total{host="example.com"} 120"#,
+            r#"local_cdn_proxy_upstream_received_bytes_total{host="example.com",kind="fill"} 3"#,
+            r#"local_cdn_proxy_upstream_latency_seconds_count{host="example.com",kind="fill"} 2"#,
+            r#"local_cdn_proxy_upstream_latency_seconds_bucket{le="0.005",host="example.com",kind="fill"} 1"#,
+            r#"local_cdn_proxy_upstream_latency_seconds_bucket{le="0.02",host="example.com",kind="fill"} 1"#,
+            r#"local_cdn_proxy_upstream_latency_seconds_bucket{le="0.04",host="example.com",kind="fill"} 2"#,
+        ] {
+            assert!(lines.contains(&line), "{line} not in:\n{text}");
+        }
+        assert!(!text.contains(r#"result="expired""#));
+        assert!(!text.contains(r#"result="failed""#));
+        assert!(!text.contains(r#"kind="forward""#));
+        assert!(text.ends_with("# EOF\n"));
+    }
+}
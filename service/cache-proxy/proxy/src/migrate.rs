@@ -0,0 +1,147 @@
+//! Offline migration of cache entries to the current format.
+//!
+//! Entries are also upgraded lazily when they are served, migration rewrites
+//! the whole cache at once, so that no older entries are left on disk.
+
+use std::path::Path;
+
+use crate::{
+    entry::{self, Loaded},
+    gc,
+};
+
+#[derive(Debug, Default)]
+pub struct MigrateStats {
+    /// Entries already in the current format.
+    pub current: usize,
+    /// Entries rewritten in the current format.
+    pub upgraded: usize,
+    /// Entries that can't be read and are removed.
+    pub evicted: usize,
+    /// Bytes of index buckets removed by compacting them.
+    pub index_reclaimed: u64,
+}
+
+/// Rewrite all entries in cache `root` in the current format, and compact
+/// its index.
+///
+/// The proxy must not be serving `root` at the same time.
+pub fn migrate(root: &Path) -> Result<MigrateStats, cacache::Error> {
+    let mut stats = MigrateStats::default();
+    for md in gc::list_index(root)? {
+        if md.key.starts_with("encoded:") {
+            continue;
+        }
+        match entry::load(root, &md)? {
+            // entries stored before the version was recorded
+            Loaded::Current(e) if md.metadata.get("version").is_none() => {
+                entry::update_metadata(root, &md.key, &entry::EntryMetadata::read(&md), &e)?;
+                stats.upgraded += 1;
+            }
+            Loaded::Current(_) => stats.current += 1,
+            Loaded::Upgraded(_) => stats.upgraded += 1,
+            Loaded::Evicted => stats.evicted += 1,
+            // vary index has no format to upgrade
+            Loaded::VaryIndex => {}
+        }
+    }
+    stats.index_reclaimed = gc::compact_index(root)?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use bytes::Bytes;
+    use http::header;
+    use http_cache_semantics::CachePolicy;
+
+    use super::*;
+
+    const BODY: &[u8] = b"console.log(1)";
+
+    fn policy() -> CachePolicy {
+        let req = http::Request::get("https://example.com/lib.js")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        let res = http::Response::builder()
+            .header(header::CACHE_CONTROL, "max-age=60")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        CachePolicy::new(&req, &res)
+    }
+
+    /// Write an entry of version 0 under `key`, with everything in content.
+    fn write_v0(root: &Path, key: &str) {
+        #[derive(serde::Serialize)]
+        struct EntryV0 {
+            policy: CachePolicy,
+            body: Bytes,
+            digest: Bytes,
+        }
+        let mut content = Vec::new();
+        let entry = EntryV0 {
+            policy: policy(),
+            body: Bytes::from_static(BODY),
+            digest: Bytes::from_static(b"digest"),
+        };
+        ciborium::into_writer(&entry, &mut content).unwrap();
+        cacache::write_sync(root, key, content).unwrap();
+    }
+
+    fn find(root: &Path, key: &str) -> cacache::Metadata {
+        cacache::index::find(root, key).unwrap().unwrap()
+    }
+
+    #[test]
+    fn upgrade_v0_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        write_v0(dir.path(), "k");
+        let stats = migrate(dir.path()).unwrap();
+        assert_eq!((stats.current, stats.upgraded, stats.evicted), (0, 1, 0));
+        // line of the entry of version 0 is compacted
+        assert!(stats.index_reclaimed > 0);
+        let md = find(dir.path(), "k");
+        assert_eq!(md.metadata["version"], entry::ENTRY_VERSION);
+        assert_eq!(entry::read_body(dir.path(), &md.integrity).unwrap(), BODY);
+
+        let stats = migrate(dir.path()).unwrap();
+        assert_eq!((stats.current, stats.upgraded, stats.evicted), (1, 0, 0));
+    }
+
+    #[test]
+    fn upgrade_unversioned_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = cacache::WriteOpts::new()
+            .metadata(serde_json::json!({ "policy": policy() }))
+            .open_sync(dir.path(), "k")
+            .unwrap();
+        writer.write_all(BODY).unwrap();
+        let integrity = writer.commit().unwrap();
+        let stats = migrate(dir.path()).unwrap();
+        assert_eq!((stats.current, stats.upgraded, stats.evicted), (0, 1, 0));
+        let md = find(dir.path(), "k");
+        assert_eq!(md.metadata["version"], entry::ENTRY_VERSION);
+        assert_eq!(md.integrity, integrity);
+    }
+
+    #[test]
+    fn skip_encoded_and_vary_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let encoded = "encoded:gzip:sha256-abc";
+        cacache::write_sync(dir.path(), encoded, b"gzip").unwrap();
+        entry::write_vary_index(dir.path(), "v", &[header::ACCEPT_LANGUAGE]).unwrap();
+        let stats = migrate(dir.path()).unwrap();
+        assert_eq!((stats.current, stats.upgraded, stats.evicted), (0, 0, 0));
+        assert_eq!(cacache::read_sync(dir.path(), encoded).unwrap(), b"gzip");
+        assert_eq!(
+            entry::read_vary_index(&find(dir.path(), "v")),
+            Some(vec![header::ACCEPT_LANGUAGE])
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! Mirror origins of an upstream host, with health tracking for failover.
+
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use http::uri::Authority;
+
+/// Time a mirror is skipped after its first failure, doubled on each
+/// consecutive failure.
+const BASE_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Mirrors are created without any origin.
+#[derive(Debug)]
+pub struct NoMirrors;
+impl Display for NoMirrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("no mirror of upstream")
+    }
+}
+impl std::error::Error for NoMirrors {}
+
+/// Mirror a response is received from, in extensions of upstream responses.
+#[derive(Debug, Clone)]
+pub(crate) struct Mirror(pub(crate) Authority);
+
+#[derive(Default)]
+struct Health {
+    failures: u32,
+    down_until: Option<Instant>,
+}
+
+struct Origin {
+    authority: Authority,
+    /// `Host` of requests sent to the mirror.
+    host: Authority,
+    health: Mutex<Health>,
+}
+
+/// Ordered mirror origins of a host, sharing the same cache.
+///
+/// Requests are sent to the first healthy mirror. A mirror is unhealthy for
+/// a while after it fails to connect or responds with server error.
+#[derive(Clone)]
+pub struct Mirrors(Arc<[Origin]>);
+impl Mirrors {
+    /// Mirrors in order of preference, fails if `authorities` is empty.
+    pub fn new(authorities: Vec<Authority>) -> Result<Self, NoMirrors> {
+        Self::with_hosts(authorities.into_iter().map(|a| (a.clone(), a)).collect())
+    }
+    /// The host itself as its only mirror.
+    pub(crate) fn single(authority: Authority) -> Self {
+        Self::from_origins(vec![(authority.clone(), authority)])
+    }
+    /// Mirrors in order of preference with the `Host` of requests sent to
+    /// each of them, which differs from the mirror authority if it serves
+    /// the host by another name, e.g. its IP address.
+    pub fn with_hosts(origins: Vec<(Authority, Authority)>) -> Result<Self, NoMirrors> {
+        match origins.is_empty() {
+            true => Err(NoMirrors),
+            false => Ok(Self::from_origins(origins)),
+        }
+    }
+    fn from_origins(origins: Vec<(Authority, Authority)>) -> Self {
+        Self(
+            origins
+                .into_iter()
+                .map(|(authority, host)| Origin {
+                    authority,
+                    host,
+                    health: Mutex::default(),
+                })
+                .collect(),
+        )
+    }
+    /// Index and authority of mirrors in the order they should be tried,
+    /// healthy ones first.
+    pub(crate) fn order(&self) -> Vec<(usize, Authority)> {
+        let now = Instant::now();
+        let mut order: Vec<_> = self
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, o)| {
+                let down_until = o.health.lock().unwrap().down_until.filter(|t| *t > now);
+                (down_until, i, o.authority.clone())
+            })
+            .collect();
+        // healthy mirrors are not down, and sorted before the others
+        order.sort_by_key(|(down_until, i, _)| (*down_until, *i));
+        order.into_iter().map(|(_, i, a)| (i, a)).collect()
+    }
+    /// Index and authority of the mirror to use when request can't be
+    /// retried.
+    pub(crate) fn preferred(&self) -> (usize, Authority) {
+        self.order().swap_remove(0)
+    }
+    /// `Host` of requests sent to mirror `index`.
+    pub(crate) fn host(&self, index: usize) -> &Authority {
+        &self.0[index].host
+    }
+    pub(crate) fn success(&self, index: usize) {
+        let mut health = self.0[index].health.lock().unwrap();
+        if health.failures > 0 {
+            tracing::info!(mirror = %self.0[index].authority, "mirror recovered");
+        }
+        *health = Health::default();
+    }
+    pub(crate) fn failure(&self, index: usize) {
+        if self.0.len() == 1 {
+            // nothing to fail over to
+            return;
+        }
+        let mut health = self.0[index].health.lock().unwrap();
+        let backoff = BASE_BACKOFF
+            .saturating_mul(1 << health.failures.min(16))
+            .min(MAX_BACKOFF);
+        health.failures += 1;
+        health.down_until = Some(Instant::now() + backoff);
+        tracing::warn!(
+            mirror = %self.0[index].authority,
+            failures = health.failures,
+            ?backoff,
+            "mirror marked unhealthy"
+        );
+    }
+}
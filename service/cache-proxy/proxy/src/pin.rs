@@ -0,0 +1,86 @@
+//! Subresource integrity pinning of cached responses.
+//!
+//! Bodies of pinned paths must match the pinned digest, they are verified
+//! before they are stored or served, so that a compromised upstream can't
+//! poison the cache.
+
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+use http::{uri::Authority, Uri};
+use ssri::Integrity;
+
+use crate::key::KeyNormalization;
+
+#[derive(Debug)]
+pub enum PinError {
+    Parse(serde_json::Error),
+    InvalidUrl(String),
+    InvalidIntegrity(String, ssri::Error),
+}
+impl Display for PinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "failed to parse integrity manifest: {e}"),
+            Self::InvalidUrl(u) => write!(f, "invalid url {u:?}, expect an absolute url"),
+            Self::InvalidIntegrity(u, e) => write!(f, "invalid integrity of {u}: {e}"),
+        }
+    }
+}
+impl std::error::Error for PinError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Parse(e) => Some(e),
+            Self::InvalidUrl(_) => None,
+            Self::InvalidIntegrity(_, e) => Some(e),
+        }
+    }
+}
+
+/// Pinned integrity of paths of one host, by path and query.
+#[derive(Debug, Clone, Default)]
+pub struct Pins(Arc<HashMap<String, Integrity>>);
+impl Pins {
+    pub fn get(&self, key: &str) -> Option<&Integrity> {
+        self.0.get(key)
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// Pins by cache keys normalized by `key`.
+    pub(crate) fn normalized(&self, key: &KeyNormalization) -> Self {
+        Self(Arc::new(
+            self.0
+                .iter()
+                .map(|(path, pin)| (key.normalize(path).into_owned(), pin.clone()))
+                .collect(),
+        ))
+    }
+}
+
+/// Parse manifest, a JSON object from URL to its SRI hash, e.g.
+/// `{"https://code.jquery.com/jquery-3.7.1.min.js": "sha256-..."}`.
+///
+/// Multiple hashes of different algorithms may be given separated by spaces,
+/// the strongest one is checked.
+pub fn parse_manifest(manifest: &str) -> Result<HashMap<Authority, Pins>, PinError> {
+    let urls: HashMap<String, String> = serde_json::from_str(manifest).map_err(PinError::Parse)?;
+    let mut hosts = HashMap::<Authority, HashMap<String, Integrity>>::new();
+    for (url, sri) in urls {
+        let uri = Uri::try_from(url.as_str()).map_err(|_| PinError::InvalidUrl(url.clone()))?;
+        let (authority, path) = match (uri.authority(), uri.path_and_query()) {
+            (Some(a), Some(p)) => (a.clone(), p.as_str().to_string()),
+            _ => return Err(PinError::InvalidUrl(url)),
+        };
+        let integrity = sri
+            .parse::<Integrity>()
+            .map_err(|e| PinError::InvalidIntegrity(url, e))?;
+        hosts.entry(authority).or_default().insert(path, integrity);
+    }
+    Ok(hosts
+        .into_iter()
+        .map(|(a, pins)| (a, Pins(Arc::new(pins))))
+        .collect())
+}
@@ -0,0 +1,178 @@
+use std::{fmt::Display, sync::Arc, time::SystemTime};
+
+use http::{header, Request, Response, StatusCode};
+use http_cache_semantics::{BeforeRequest, CachePolicy};
+use hyper::body::Incoming;
+use ssri::Integrity;
+use tower_service::Service;
+
+use crate::{flight::Flight, stale, CacheProxy, FillOutcome, Filled, ProxyError, UpstreamBody};
+
+/// Result of prefetching one path into cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prefetched {
+    /// Cached response is already fresh.
+    Fresh,
+    /// Response is fetched from upstream.
+    Fetched,
+    /// Stale response is revalidated with upstream.
+    Revalidated,
+    /// Upstream can't be reached, stale response is kept.
+    Stale,
+    /// Upstream responded with an unsuccessful status.
+    Status(StatusCode),
+    /// Response is stored but can't be served from cache.
+    Uncacheable,
+    /// Another request updating the entry stored nothing.
+    NotStored,
+}
+impl Prefetched {
+    /// Whether the path can be served from cache afterwards.
+    pub fn is_success(self) -> bool {
+        matches!(self, Self::Fresh | Self::Fetched | Self::Revalidated)
+    }
+}
+impl Display for Prefetched {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fresh => f.write_str("already cached"),
+            Self::Fetched => f.write_str("fetched"),
+            Self::Revalidated => f.write_str("revalidated"),
+            Self::Stale => f.write_str("failed to revalidate, stale response kept"),
+            Self::Status(s) => write!(f, "upstream responded with {s}"),
+            Self::Uncacheable => f.write_str("response can't be served from cache"),
+            Self::NotStored => f.write_str("concurrent request stored nothing"),
+        }
+    }
+}
+
+/// Status of response stored with `policy`, if it can be served for `req`.
+fn stored_status(
+    policy: &CachePolicy,
+    req: &http::request::Parts,
+    now: SystemTime,
+) -> Option<StatusCode> {
+    match policy.before_request(req, now) {
+        BeforeRequest::Fresh(pts) => Some(pts.status),
+        BeforeRequest::Stale { .. } if policy.is_storable() => {
+            stale::offline(policy, req, now).map(|pts| pts.status)
+        }
+        BeforeRequest::Stale { .. } => None,
+    }
+}
+
+impl<S, E> CacheProxy<S>
+where
+    S: Clone + Send + 'static,
+    S: Service<Request<UpstreamBody>, Response = Response<Incoming>, Error = E>,
+    S::Future: Send,
+    E: Display + Send + 'static,
+{
+    /// Fetch response of `path` into cache, as if it is requested by a client
+    /// without any request headers.
+    pub async fn prefetch(&mut self, path: &str) -> Result<Prefetched, ProxyError<E>> {
+        let mut req = Request::get(path)
+            .header(header::HOST, self.authority.as_str())
+            .body(())
+            .map_err(|e| ProxyError::InvalidPath(path.to_string(), e))?
+            .into_parts()
+            .0;
+        self.normalize_uri(&mut req);
+        let primary = self.cache_key(&req).into_owned();
+        let (key, entry) = self
+            .find_entry(&primary, &req.headers)
+            .await
+            .map_err(ProxyError::ReadCache)?;
+        let entry = match entry {
+            Some(e) if self.verify(&primary, &e).await => Some(e),
+            _ => None,
+        };
+        if let Some(entry) = &entry {
+            if let BeforeRequest::Fresh(_) = entry.policy.before_request(&req, SystemTime::now()) {
+                return Ok(Prefetched::Fresh);
+            }
+        }
+        let flight = match self.in_flight.join(&key) {
+            Flight::Leader(g) => g,
+            Flight::Follower(f) => {
+                tracing::debug!(key, "entry is already being updated");
+                let old = match &entry {
+                    Some(_) => self.stored_version(&key).await?,
+                    None => None,
+                };
+                f.wait().await;
+                return self.prefetched_by_other(&primary, &req, old).await;
+            }
+        };
+        let upstream_req = self.upstream_request(&req)?;
+        let filled = match entry {
+            Some(entry) => self.update_entry(&key, entry, upstream_req, flight).await?,
+            None => self.get_missing(&key, upstream_req, flight).await?,
+        };
+        let (policy, fetched) = match filled {
+            Filled::Stored(entry, outcome) => (
+                entry.policy,
+                match outcome {
+                    // bodies of pinned paths are stored before they are returned
+                    FillOutcome::Updated => Prefetched::Fetched,
+                    FillOutcome::NotModified => Prefetched::Revalidated,
+                    FillOutcome::Skipped => Prefetched::Fresh,
+                },
+            ),
+            Filled::Streaming(policy, body) => {
+                body.drain().await;
+                (policy, Prefetched::Fetched)
+            }
+            Filled::Stale(_, _) => return Ok(Prefetched::Stale),
+        };
+        Ok(match stored_status(&policy, &req, SystemTime::now()) {
+            Some(s) if s.is_success() => fetched,
+            Some(s) => Prefetched::Status(s),
+            None => Prefetched::Uncacheable,
+        })
+    }
+    /// Integrity and index time of entry `key`, which change whenever the
+    /// entry is stored, even if its body or policy doesn't.
+    async fn stored_version(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<(Integrity, u128)>, ProxyError<E>> {
+        let (root, key) = (Arc::clone(&self.root), key.to_string());
+        let md = crate::blocking(move || cacache::index::find(&root, &key))
+            .await
+            .map_err(ProxyError::ReadCache)?;
+        Ok(md.map(|md| (md.integrity, md.time)))
+    }
+    /// Result of prefetching `req` by another request, according to the
+    /// entry it stored. `old` is the [`Self::stored_version`] of the entry
+    /// before it was updated.
+    async fn prefetched_by_other(
+        &mut self,
+        primary: &str,
+        req: &http::request::Parts,
+        old: Option<(Integrity, u128)>,
+    ) -> Result<Prefetched, ProxyError<E>> {
+        let (key, entry) = self
+            .find_entry(primary, &req.headers)
+            .await
+            .map_err(ProxyError::ReadCache)?;
+        let Some(entry) = entry else {
+            return Ok(Prefetched::NotStored);
+        };
+        let fetched = match old {
+            Some(old) => {
+                // the other request failed and kept the stale entry
+                if self.stored_version(&key).await?.as_ref() == Some(&old) {
+                    return Ok(Prefetched::Stale);
+                }
+                Prefetched::Revalidated
+            }
+            None => Prefetched::Fetched,
+        };
+        Ok(match stored_status(&entry.policy, req, SystemTime::now()) {
+            Some(s) if s.is_success() => fetched,
+            Some(s) => Prefetched::Status(s),
+            None => Prefetched::Uncacheable,
+        })
+    }
+}
@@ -0,0 +1,400 @@
+use std::time::SystemTime;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+
+/// Most ranges served in one response, after overlapping ranges are merged.
+const MAX_RANGES: usize = 16;
+
+/// Byte range of a body, both ends are inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+/// Parse `Range` header for a body of `len` bytes.
+///
+/// Returns `None` if the header is invalid or not in bytes, so that it is
+/// ignored. Ranges that can't be satisfied are skipped, an empty list means
+/// none of them can be. Overlapping and adjacent ranges are merged, and the
+/// header is also ignored if there are still more than [`MAX_RANGES`], or
+/// more bytes than the body are requested, so that a small header can't
+/// make the response many times larger than the body.
+fn parse(value: &HeaderValue, len: u64) -> Option<Vec<ByteRange>> {
+    let (unit, set) = value.to_str().ok()?.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    let mut empty = true;
+    for spec in set.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        empty = false;
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        if first.is_empty() {
+            let suffix: u64 = last.parse().ok()?;
+            if suffix > 0 && len > 0 {
+                ranges.push(ByteRange {
+                    start: len.saturating_sub(suffix),
+                    end: len - 1,
+                });
+            }
+        } else {
+            let start: u64 = first.parse().ok()?;
+            let end = match last {
+                "" => None,
+                l => Some(l.parse::<u64>().ok()?),
+            };
+            if end.is_some_and(|e| e < start) {
+                return None;
+            }
+            if start < len {
+                ranges.push(ByteRange {
+                    start,
+                    end: end.map_or(len - 1, |e| e.min(len - 1)),
+                });
+            }
+        }
+    }
+    if empty {
+        return None;
+    }
+    let requested = ranges.iter().map(|r| r.end - r.start + 1).sum::<u64>();
+    let ranges = merge(ranges);
+    if requested > len || ranges.len() > MAX_RANGES {
+        return None;
+    }
+    Some(ranges)
+}
+
+/// Sort `ranges` and merge overlapping or adjacent ones.
+fn merge(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for r in ranges {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end.saturating_add(1) => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    merged
+}
+
+fn parse_date(v: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(v.trim()).ok()
+}
+
+/// Whether `If-Range` validator matches the stored response.
+///
+/// Entity tags use strong comparison, dates must equal `Last-Modified` exactly.
+fn if_range_matches(value: &HeaderValue, resp: &HeaderMap) -> bool {
+    let value = match value.to_str() {
+        Ok(v) => v.trim(),
+        Err(_) => return false,
+    };
+    let validator = |name| resp.get(name).and_then(|v| v.to_str().ok());
+    if value.starts_with('"') {
+        validator(header::ETAG).is_some_and(|e| e.trim() == value)
+    } else if value.starts_with("W/") {
+        false
+    } else {
+        match (
+            parse_date(value),
+            validator(header::LAST_MODIFIED).and_then(parse_date),
+        ) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+fn content_range(r: ByteRange, len: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!("bytes {}-{}/{len}", r.start, r.end)).unwrap()
+}
+
+/// Part of the body of a partial response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Part {
+    /// Bytes not in the stored body, like headers of parts of a multipart body.
+    Literal(Bytes),
+    /// `len` bytes of the stored body from `offset`.
+    Stored { offset: u64, len: u64 },
+}
+impl Part {
+    fn stored(r: ByteRange) -> Self {
+        Self::Stored {
+            offset: r.start,
+            len: r.end - r.start + 1,
+        }
+    }
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            Self::Literal(b) => b.len() as u64,
+            Self::Stored { len, .. } => *len,
+        }
+    }
+}
+
+fn multipart(
+    ranges: &[ByteRange],
+    content_type: Option<&HeaderValue>,
+    len: u64,
+    boundary: &str,
+) -> Vec<Part> {
+    let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+    for r in ranges {
+        let mut buf = BytesMut::new();
+        buf.put_slice(format!("\r\n--{boundary}\r\n").as_bytes());
+        if let Some(ct) = content_type {
+            buf.put_slice(b"Content-Type: ");
+            buf.put_slice(ct.as_bytes());
+            buf.put_slice(b"\r\n");
+        }
+        buf.put_slice(b"Content-Range: ");
+        buf.put_slice(content_range(*r, len).as_bytes());
+        buf.put_slice(b"\r\n\r\n");
+        parts.push(Part::Literal(buf.freeze()));
+        parts.push(Part::stored(*r));
+    }
+    parts.push(Part::Literal(Bytes::from(format!(
+        "\r\n--{boundary}--\r\n"
+    ))));
+    parts
+}
+
+/// Advertise that ranges of response `pts` can be requested.
+pub(crate) fn accept_ranges(pts: &mut http::response::Parts) {
+    if pts.status == StatusCode::OK {
+        pts.headers
+            .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+}
+
+/// Parts of the body of cached response `pts` with body of `len` bytes to
+/// send for request with `req` headers, `pts` is updated to be a partial
+/// response.
+///
+/// Returns `None` if the full response is sent, because the request has no
+/// valid `Range` header or `If-Range` doesn't match.
+pub(crate) fn respond(
+    req: &HeaderMap,
+    pts: &mut http::response::Parts,
+    len: u64,
+) -> Option<Vec<Part>> {
+    if pts.status != StatusCode::OK {
+        return None;
+    }
+    let range = req.get(header::RANGE)?;
+    if let Some(v) = req.get(header::IF_RANGE) {
+        if !if_range_matches(v, &pts.headers) {
+            tracing::debug!(if_range = ?v, "validator changed, ignore range");
+            return None;
+        }
+    }
+    let ranges = match parse(range, len) {
+        Some(r) => r,
+        None => {
+            tracing::debug!(?range, "invalid range, ignored");
+            return None;
+        }
+    };
+    let parts = match ranges.as_slice() {
+        [] => {
+            tracing::debug!(?range, len, "range not satisfiable");
+            pts.status = StatusCode::RANGE_NOT_SATISFIABLE;
+            pts.headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{len}")).unwrap(),
+            );
+            pts.headers.remove(header::CONTENT_TYPE);
+            Vec::new()
+        }
+        [r] => {
+            tracing::debug!(?range, "serve single range");
+            pts.status = StatusCode::PARTIAL_CONTENT;
+            pts.headers
+                .insert(header::CONTENT_RANGE, content_range(*r, len));
+            vec![Part::stored(*r)]
+        }
+        ranges => {
+            tracing::debug!(?range, "serve multiple ranges");
+            let boundary = format!(
+                "local_cdn-{:x}",
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos()
+            );
+            let parts = multipart(
+                ranges,
+                pts.headers.get(header::CONTENT_TYPE),
+                len,
+                &boundary,
+            );
+            pts.status = StatusCode::PARTIAL_CONTENT;
+            pts.headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_str(&format!("multipart/byteranges; boundary={boundary}"))
+                    .unwrap(),
+            );
+            parts
+        }
+    };
+    let size = parts.iter().map(Part::len).sum::<u64>();
+    pts.headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+    Some(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+        parse(&HeaderValue::from_str(header).unwrap(), len)
+            .map(|rs| rs.into_iter().map(|r| (r.start, r.end)).collect())
+    }
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(ranges("bytes=0-4", 10), Some(vec![(0, 4)]));
+        assert_eq!(ranges("bytes=5-", 10), Some(vec![(5, 9)]));
+        assert_eq!(ranges("bytes=5-100", 10), Some(vec![(5, 9)]));
+        assert_eq!(ranges("bytes=0-1, 5-6", 10), Some(vec![(0, 1), (5, 6)]));
+        assert_eq!(ranges("BYTES=0-0", 10), Some(vec![(0, 0)]));
+        assert_eq!(ranges("items=0-4", 10), None);
+        assert_eq!(ranges("bytes=", 10), None);
+        assert_eq!(ranges("bytes=a-b", 10), None);
+    }
+
+    #[test]
+    fn parse_suffix_ranges() {
+        assert_eq!(ranges("bytes=-3", 10), Some(vec![(7, 9)]));
+        assert_eq!(ranges("bytes=-20", 10), Some(vec![(0, 9)]));
+        assert_eq!(ranges("bytes=-0", 10), Some(vec![]));
+    }
+
+    #[test]
+    fn parse_end_before_start() {
+        assert_eq!(ranges("bytes=5-2", 10), None);
+        assert_eq!(ranges("bytes=0-1,5-2", 10), None);
+    }
+
+    #[test]
+    fn parse_unsatisfiable() {
+        assert_eq!(ranges("bytes=10-", 10), Some(vec![]));
+        assert_eq!(ranges("bytes=0-", 0), Some(vec![]));
+        assert_eq!(ranges("bytes=-5", 0), Some(vec![]));
+        assert_eq!(ranges("bytes=20-30,0-1", 10), Some(vec![(0, 1)]));
+    }
+
+    #[test]
+    fn parse_merges_ranges() {
+        assert_eq!(ranges("bytes=3-8,0-4,9-9", 20), Some(vec![(0, 9)]));
+        assert_eq!(ranges("bytes=6-7,0-1", 20), Some(vec![(0, 1), (6, 7)]));
+    }
+
+    #[test]
+    fn parse_rejects_amplification() {
+        assert_eq!(
+            ranges(&format!("bytes={}", ["0-"; 1000].join(",")), 10),
+            None
+        );
+        let spaced = |n: u64| {
+            (0..n)
+                .map(|i| format!("{0}-{0}", i * 2))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        assert_eq!(
+            ranges(&format!("bytes={}", spaced(16)), 100).map(|r| r.len()),
+            Some(16)
+        );
+        assert_eq!(ranges(&format!("bytes={}", spaced(17)), 100), None);
+    }
+
+    fn response(headers: &[(header::HeaderName, &str)]) -> http::response::Parts {
+        let mut builder = http::Response::builder().status(StatusCode::OK);
+        for (name, value) in headers {
+            builder = builder.header(name, *value);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    fn request(headers: &[(header::HeaderName, &str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(n, v)| (n.clone(), HeaderValue::from_str(v).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn if_range() {
+        const DATE: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let stored = [(header::ETAG, "\"abc\""), (header::LAST_MODIFIED, DATE)];
+        let status = |if_range: &str| {
+            let req = request(&[(header::RANGE, "bytes=0-1"), (header::IF_RANGE, if_range)]);
+            let mut pts = response(&stored);
+            respond(&req, &mut pts, 10);
+            pts.status
+        };
+        assert_eq!(status("\"abc\""), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(status(DATE), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(status("\"xyz\""), StatusCode::OK);
+        assert_eq!(status("W/\"abc\""), StatusCode::OK);
+        assert_eq!(status("Thu, 22 Oct 2015 07:28:00 GMT"), StatusCode::OK);
+    }
+
+    #[test]
+    fn respond_ranges() {
+        let stored = [(header::CONTENT_TYPE, "text/plain")];
+        let respond = |range: &str| {
+            let mut pts = response(&stored);
+            let parts = respond(&request(&[(header::RANGE, range)]), &mut pts, 10);
+            (pts, parts)
+        };
+
+        let (pts, parts) = respond("bytes=2-4");
+        assert_eq!(pts.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(pts.headers[header::CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(pts.headers[header::CONTENT_LENGTH], "3");
+        assert_eq!(parts, Some(vec![Part::Stored { offset: 2, len: 3 }]));
+
+        let (pts, parts) = respond("bytes=0-1,-2");
+        let parts = parts.unwrap();
+        let ct = pts.headers[header::CONTENT_TYPE].to_str().unwrap();
+        let boundary = ct.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let part = |range: &str| {
+            Part::Literal(Bytes::from(format!(
+                "\r\n--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes {range}/10\r\n\r\n"
+            )))
+        };
+        assert_eq!(
+            parts,
+            [
+                part("0-1"),
+                Part::Stored { offset: 0, len: 2 },
+                part("8-9"),
+                Part::Stored { offset: 8, len: 2 },
+                Part::Literal(Bytes::from(format!("\r\n--{boundary}--\r\n"))),
+            ]
+        );
+        let len = parts.iter().map(Part::len).sum::<u64>();
+        assert_eq!(
+            pts.headers[header::CONTENT_LENGTH],
+            len.to_string().as_str()
+        );
+
+        let (pts, parts) = respond("bytes=10-");
+        assert_eq!(pts.status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(pts.headers[header::CONTENT_RANGE], "bytes */10");
+        assert_eq!(pts.headers[header::CONTENT_LENGTH], "0");
+        assert!(pts.headers.get(header::CONTENT_TYPE).is_none());
+        assert_eq!(parts, Some(Vec::new()));
+
+        let (pts, parts) = respond("items=0-1");
+        assert_eq!(pts.status, StatusCode::OK);
+        assert_eq!(parts, None);
+    }
+}
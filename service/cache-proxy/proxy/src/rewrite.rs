@@ -0,0 +1,242 @@
+//! Rewriting of request headers sent to upstream.
+//!
+//! Headers are rewritten before cache lookup, so that stored policies and
+//! variants are selected by the headers upstream actually received.
+
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+use http::{header, HeaderMap, HeaderName, HeaderValue};
+
+/// User agent sent to upstream unless it is configured.
+const DEFAULT_USER_AGENT: HeaderValue = HeaderValue::from_static("curl");
+
+/// Client headers removed in privacy mode, which may identify or track the
+/// client.
+const IDENTIFYING_HEADERS: [&str; 16] = [
+    "user-agent",
+    "accept-language",
+    "cookie",
+    "authorization",
+    "proxy-authorization",
+    "referer",
+    "origin",
+    "from",
+    "dnt",
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-real-ip",
+    "via",
+    "sec-gpc",
+    "x-client-data",
+];
+
+#[derive(Debug)]
+pub enum RewriteError {
+    Parse(serde_json::Error),
+    InvalidName(String),
+    InvalidValue(String),
+    Host,
+}
+impl Display for RewriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "failed to parse header rules: {e}"),
+            Self::InvalidName(n) => write!(f, "invalid header name {n:?}"),
+            Self::InvalidValue(n) => write!(f, "invalid value of header {n:?}"),
+            Self::Host => f.write_str("host header can't be rewritten"),
+        }
+    }
+}
+impl std::error::Error for RewriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    set: HashMap<String, String>,
+    #[serde(default)]
+    remove: Vec<String>,
+    #[serde(default)]
+    pass: Vec<String>,
+    #[serde(default)]
+    privacy: bool,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    set: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+    pass: Vec<HeaderName>,
+    privacy: bool,
+}
+
+/// Rules rewriting headers of requests sent to upstream.
+///
+/// Headers in `set` replace those of client, headers in `remove` are never
+/// sent, and in privacy mode identifying client headers are removed unless
+/// they are listed in `pass`. `User-Agent` is set to `curl` if it isn't
+/// configured by any of them.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRewrite(Arc<Inner>);
+impl HeaderRewrite {
+    /// Parse rules from a JSON object, e.g.
+    /// `{"set": {"User-Agent": "local_cdn"}, "remove": ["Cookie"], "pass": ["Accept-Language"], "privacy": true}`.
+    pub fn parse(rules: &str) -> Result<Self, RewriteError> {
+        let config: Config = serde_json::from_str(rules).map_err(RewriteError::Parse)?;
+        let name = |n: &str| {
+            let name = HeaderName::try_from(n).map_err(|_| RewriteError::InvalidName(n.into()))?;
+            match name == header::HOST {
+                true => Err(RewriteError::Host),
+                false => Ok(name),
+            }
+        };
+        Ok(Self(Arc::new(Inner {
+            set: config
+                .set
+                .iter()
+                .map(|(n, v)| {
+                    let value = HeaderValue::try_from(v.as_str())
+                        .map_err(|_| RewriteError::InvalidValue(n.clone()))?;
+                    Ok((name(n)?, value))
+                })
+                .collect::<Result<_, RewriteError>>()?,
+            remove: config
+                .remove
+                .iter()
+                .map(|n| name(n))
+                .collect::<Result<_, _>>()?,
+            pass: config
+                .pass
+                .iter()
+                .map(|n| name(n))
+                .collect::<Result<_, _>>()?,
+            privacy: config.privacy,
+        })))
+    }
+    fn configures(&self, name: &HeaderName) -> bool {
+        let inner = &self.0;
+        inner.set.iter().any(|(n, _)| n == name)
+            || inner.remove.contains(name)
+            || inner.pass.contains(name)
+    }
+    /// Rewrite `headers` of request to upstream.
+    pub(crate) fn apply(&self, headers: &mut HeaderMap) {
+        let inner = &self.0;
+        if inner.privacy {
+            for name in IDENTIFYING_HEADERS {
+                let name = HeaderName::from_static(name);
+                if !inner.pass.contains(&name) {
+                    headers.remove(name);
+                }
+            }
+        }
+        for name in &inner.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &inner.set {
+            headers.insert(name.clone(), value.clone());
+        }
+        if !self.configures(&header::USER_AGENT) {
+            headers.insert(header::USER_AGENT, DEFAULT_USER_AGENT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, HeaderValue::from_static("browser"));
+        headers.insert(header::ACCEPT_LANGUAGE, HeaderValue::from_static("en"));
+        headers.insert(header::COOKIE, HeaderValue::from_static("id=1"));
+        headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+        headers
+    }
+
+    fn rewritten(rules: &str) -> HeaderMap {
+        let mut headers = client_headers();
+        HeaderRewrite::parse(rules).unwrap().apply(&mut headers);
+        headers
+    }
+
+    #[test]
+    fn default_user_agent() {
+        let headers = rewritten("{}");
+        assert_eq!(headers[header::USER_AGENT], "curl");
+        assert_eq!(headers[header::COOKIE], "id=1");
+
+        let mut headers = client_headers();
+        HeaderRewrite::default().apply(&mut headers);
+        assert_eq!(headers[header::USER_AGENT], "curl");
+    }
+
+    #[test]
+    fn privacy_removes_identifying_headers() {
+        let headers = rewritten(r#"{"privacy": true}"#);
+        assert!(!headers.contains_key(header::ACCEPT_LANGUAGE));
+        assert!(!headers.contains_key(header::COOKIE));
+        assert_eq!(headers[header::ACCEPT], "*/*");
+        assert_eq!(headers[header::USER_AGENT], "curl");
+    }
+
+    #[test]
+    fn privacy_passes_listed_headers() {
+        let headers = rewritten(r#"{"privacy": true, "pass": ["Accept-Language", "User-Agent"]}"#);
+        assert_eq!(headers[header::ACCEPT_LANGUAGE], "en");
+        assert_eq!(headers[header::USER_AGENT], "browser");
+        assert!(!headers.contains_key(header::COOKIE));
+    }
+
+    #[test]
+    fn set_and_remove() {
+        let headers = rewritten(r#"{"set": {"User-Agent": "local_cdn"}, "remove": ["Cookie"]}"#);
+        assert_eq!(headers[header::USER_AGENT], "local_cdn");
+        assert!(!headers.contains_key(header::COOKIE));
+
+        // removed user agent isn't replaced by the default
+        let headers = rewritten(r#"{"remove": ["User-Agent"]}"#);
+        assert!(!headers.contains_key(header::USER_AGENT));
+
+        // set headers take precedence over removed ones, even in privacy mode
+        let headers =
+            rewritten(r#"{"set": {"Cookie": "id=2"}, "remove": ["Cookie"], "privacy": true}"#);
+        let values: Vec<_> = headers.get_all(header::COOKIE).iter().collect();
+        assert_eq!(values, ["id=2"]);
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for rules in [
+            r#"{"set": {"Host": "example.com"}}"#,
+            r#"{"remove": ["host"]}"#,
+            r#"{"pass": ["HOST"]}"#,
+        ] {
+            assert!(matches!(
+                HeaderRewrite::parse(rules),
+                Err(RewriteError::Host)
+            ));
+        }
+        assert!(matches!(
+            HeaderRewrite::parse(r#"{"remove": ["bad name"]}"#),
+            Err(RewriteError::InvalidName(_))
+        ));
+        assert!(matches!(
+            HeaderRewrite::parse(r#"{"set": {"X-Id": "a\nb"}}"#),
+            Err(RewriteError::InvalidValue(_))
+        ));
+        assert!(matches!(
+            HeaderRewrite::parse(r#"{"unknown": true}"#),
+            Err(RewriteError::Parse(_))
+        ));
+    }
+}
@@ -0,0 +1,277 @@
+//! Per-path rules overriding cache policy of upstream responses.
+//!
+//! Rules rewrite `Cache-Control` of upstream responses before their
+//! [`CachePolicy`] is created, so the overridden policy is stored and used
+//! when the entry is looked up or revalidated.
+
+use std::{fmt::Display, sync::Arc, time::SystemTime};
+
+use http::{header, uri::Authority, HeaderMap, HeaderValue};
+use http_cache_semantics::CachePolicy;
+
+/// Time to live of immutable responses.
+const IMMUTABLE_TTL: u64 = 365 * 24 * 3600;
+
+#[derive(Debug)]
+pub enum RuleError {
+    Parse(serde_json::Error),
+    InvalidHost(String, http::uri::InvalidUri),
+    InvalidTtl(String),
+}
+impl Display for RuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "failed to parse cache rules: {e}"),
+            Self::InvalidHost(h, e) => write!(f, "invalid host {h:?} of cache rule: {e}"),
+            Self::InvalidTtl(p) => write!(f, "minimum ttl is greater than maximum in rule {p:?}"),
+        }
+    }
+}
+impl std::error::Error for RuleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Parse(e) => Some(e),
+            Self::InvalidHost(_, e) => Some(e),
+            Self::InvalidTtl(_) => None,
+        }
+    }
+}
+
+/// Whether `path` matches glob `pattern`.
+///
+/// `*` matches any characters except `/`, `**` matches any characters,
+/// and `?` matches one character except `/`.
+pub(crate) fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|c| *c == b'/').unwrap_or(path.len());
+            (0..=segment).any(|i| glob_match(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => match path {
+            [c, path @ ..] if *c != b'/' => glob_match(rest, path),
+            _ => false,
+        },
+        [p, rest @ ..] => match path {
+            [c, path @ ..] if c == p => glob_match(rest, path),
+            _ => false,
+        },
+    }
+}
+
+/// Rule overriding cache policy of paths matching `path`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Glob of request path, see [`glob_match`].
+    pub path: String,
+    /// Only apply to this upstream host, all hosts if not given.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Forward requests to upstream without caching.
+    #[serde(default)]
+    pub bypass: bool,
+    /// Response never changes, it is fresh for a year and never revalidated
+    /// even if requested by client.
+    #[serde(default)]
+    pub immutable: bool,
+    /// Minimum time to live in seconds.
+    #[serde(default)]
+    pub min_ttl: Option<u64>,
+    /// Maximum time to live in seconds.
+    #[serde(default)]
+    pub max_ttl: Option<u64>,
+    /// Cache responses with `no-store`.
+    #[serde(default)]
+    pub ignore_no_store: bool,
+    /// Cache responses with `private`.
+    #[serde(default)]
+    pub ignore_private: bool,
+}
+impl Rule {
+    fn matches(&self, path: &str) -> bool {
+        glob_match(self.path.as_bytes(), path.as_bytes())
+    }
+    /// Rewrite `Cache-Control` of response `resp` to request `req`.
+    pub(crate) fn apply(&self, req: &http::request::Parts, resp: &mut http::response::Parts) {
+        let mut directives: Vec<String> = resp
+            .headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|d| d.trim().to_string())
+            .filter(|d| !d.is_empty())
+            .collect();
+        let name = |d: &str| {
+            d.split_once('=')
+                .map_or(d, |(n, _)| n)
+                .trim()
+                .to_ascii_lowercase()
+        };
+        if self.ignore_no_store {
+            directives.retain(|d| name(d) != "no-store");
+        }
+        if self.ignore_private {
+            directives.retain(|d| name(d) != "private");
+        }
+        let max_age = if self.immutable {
+            directives.retain(|d| !matches!(name(d).as_str(), "no-cache" | "must-revalidate"));
+            Some(IMMUTABLE_TTL)
+        } else if self.min_ttl.is_some() || self.max_ttl.is_some() {
+            // evaluate policy with directives removed above
+            let mut headers = resp.headers.clone();
+            set_cache_control(&mut headers, &directives);
+            let mut evaluated = http::Response::new(());
+            *evaluated.status_mut() = resp.status;
+            *evaluated.headers_mut() = headers;
+            let now = SystemTime::now();
+            let policy = CachePolicy::new(req, &evaluated);
+            let ttl = policy.time_to_live(now).as_secs();
+            let clamped = ttl
+                .max(self.min_ttl.unwrap_or(0))
+                .min(self.max_ttl.unwrap_or(u64::MAX));
+            // max-age counts from the age of the response when it is received
+            (clamped != ttl).then(|| clamped + policy.age(now).as_secs())
+        } else {
+            None
+        };
+        if let Some(max_age) = max_age {
+            directives.retain(|d| !matches!(name(d).as_str(), "max-age" | "s-maxage"));
+            directives.push(format!("max-age={max_age}"));
+            if self.immutable {
+                directives.retain(|d| name(d) != "immutable");
+                directives.push("immutable".to_string());
+            }
+        }
+        set_cache_control(&mut resp.headers, &directives);
+    }
+}
+
+fn set_cache_control(headers: &mut HeaderMap, directives: &[String]) {
+    headers.remove(header::CACHE_CONTROL);
+    if let Ok(v) = HeaderValue::from_str(&directives.join(", ")) {
+        if !v.is_empty() {
+            headers.insert(header::CACHE_CONTROL, v);
+        }
+    }
+}
+
+/// Rules of one host, the first matching rule is used.
+#[derive(Debug, Clone, Default)]
+pub struct Rules(Arc<Vec<Rule>>);
+impl Rules {
+    /// Parse rules from a JSON array of [`Rule`].
+    pub fn parse(rules: &str) -> Result<Self, RuleError> {
+        let rules: Vec<Rule> = serde_json::from_str(rules).map_err(RuleError::Parse)?;
+        for r in &rules {
+            if let Some(h) = &r.host {
+                Authority::try_from(h.as_str())
+                    .map_err(|e| RuleError::InvalidHost(h.clone(), e))?;
+            }
+            if r.min_ttl.zip(r.max_ttl).is_some_and(|(min, max)| min > max) {
+                return Err(RuleError::InvalidTtl(r.path.clone()));
+            }
+        }
+        Ok(Self(Arc::new(rules)))
+    }
+    /// Rules applying to `authority`.
+    pub fn for_host(&self, authority: &Authority) -> Self {
+        Self(Arc::new(
+            self.0
+                .iter()
+                .filter(|r| {
+                    r.host
+                        .as_deref()
+                        .is_none_or(|h| h.eq_ignore_ascii_case(authority.as_str()))
+                })
+                .cloned()
+                .collect(),
+        ))
+    }
+    /// First rule matching request `path`.
+    pub fn find(&self, path: &str) -> Option<&Rule> {
+        self.0.iter().find(|r| r.matches(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        glob_match(pattern.as_bytes(), path.as_bytes())
+    }
+
+    #[test]
+    fn glob_literal() {
+        assert!(matches("/a/b.js", "/a/b.js"));
+        assert!(!matches("/a/b.js", "/a/b.jsx"));
+        assert!(!matches("/a/b.js", "/a/b"));
+        assert!(matches("", ""));
+        assert!(!matches("", "/"));
+    }
+
+    #[test]
+    fn glob_star() {
+        assert!(matches("/static/*.js", "/static/app.js"));
+        assert!(matches("/static/*.js", "/static/.js"));
+        assert!(!matches("/static/*.js", "/static/lib/app.js"));
+        assert!(matches("/*/app.js", "/v1/app.js"));
+        assert!(!matches("/*/app.js", "/v1/v2/app.js"));
+        assert!(matches("/static/*", "/static/"));
+        assert!(!matches("/static/*", "/static/a/"));
+    }
+
+    #[test]
+    fn glob_double_star() {
+        assert!(matches("/static/**", "/static/lib/app.js"));
+        assert!(matches("/static/**", "/static/"));
+        assert!(matches("/**.js", "/a/b/c.js"));
+        assert!(matches("/**/app.js", "/a/b/app.js"));
+        assert!(!matches("/**/app.js", "/a/b/app.css"));
+        assert!(matches("**", ""));
+    }
+
+    #[test]
+    fn glob_question_mark() {
+        assert!(matches("/v?/app.js", "/v1/app.js"));
+        assert!(!matches("/v?/app.js", "/v10/app.js"));
+        assert!(!matches("/v?/app.js", "/v/app.js"));
+        assert!(!matches("/a?b", "/a/b"));
+    }
+
+    #[test]
+    fn rules_for_host() {
+        let rules = Rules::parse(
+            r#"[
+                {"path": "/api/**", "host": "api.example.com", "bypass": true},
+                {"path": "/**.js", "immutable": true},
+                {"path": "/**"}
+            ]"#,
+        )
+        .unwrap();
+        let api = rules.for_host(&Authority::from_static("API.example.com"));
+        assert!(api.find("/api/v1").unwrap().bypass);
+        assert!(api.find("/a/b.js").unwrap().immutable);
+        let other = rules.for_host(&Authority::from_static("example.com"));
+        assert_eq!(other.find("/api/v1").unwrap().path, "/**");
+    }
+
+    #[test]
+    fn rules_invalid() {
+        assert!(matches!(
+            Rules::parse(r#"[{"path": "/**", "min_ttl": 10, "max_ttl": 5}]"#),
+            Err(RuleError::InvalidTtl(_))
+        ));
+        assert!(matches!(
+            Rules::parse(r#"[{"path": "/**", "host": "a b"}]"#),
+            Err(RuleError::InvalidHost(..))
+        ));
+        assert!(matches!(
+            Rules::parse(r#"[{"path": "/**", "unknown": 1}]"#),
+            Err(RuleError::Parse(_))
+        ));
+    }
+}
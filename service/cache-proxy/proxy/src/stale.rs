@@ -0,0 +1,337 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use http_cache_semantics::{AfterResponse, BeforeRequest, CachePolicy};
+
+const WARN_STALE: &str = r#"110 - "Response is Stale""#;
+const WARN_REVALIDATION_FAILED: &str = r#"111 - "Revalidation Failed""#;
+const WARN_DISCONNECTED: &str = r#"112 - "Disconnected Operation""#;
+
+/// Switch of offline mode, shared by all proxy instances.
+///
+/// In offline mode, any cached response is served regardless of its
+/// freshness, without contacting upstream. Requests of paths that are not
+/// cached fail with [`ProxyError::Offline`](crate::ProxyError::Offline).
+#[derive(Clone, Default)]
+pub struct OfflineMode(Arc<AtomicBool>);
+impl OfflineMode {
+    pub fn new(enabled: bool) -> Self {
+        Self(Arc::new(AtomicBool::new(enabled)))
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+    pub fn set(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed)
+    }
+    /// Toggle offline mode, returns whether it is enabled afterwards.
+    pub fn toggle(&self) -> bool {
+        !self.0.fetch_xor(true, Ordering::Relaxed)
+    }
+}
+
+fn directive_secs(headers: &HeaderMap, name: &str) -> Option<Duration> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|d| d.trim().split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+        .and_then(|(_, v)| v.trim().trim_matches('"').parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// How long the response has been stale.
+fn staleness(policy: &CachePolicy, now: SystemTime) -> Duration {
+    // age before the response is received is the Age header of response,
+    // and time to live at that time is the rest of freshness lifetime
+    let max_age = policy.time_to_live(SystemTime::UNIX_EPOCH) + policy.age(SystemTime::UNIX_EPOCH);
+    policy.age(now).saturating_sub(max_age)
+}
+
+/// Stored response of `policy` for `req`, regardless of its freshness.
+///
+/// `CachePolicy` only gives stored response headers when it is usable, so
+/// evaluate it as if at the time the response was received, with a request
+/// accepting stale responses. Responses with `must-revalidate` that have no
+/// freshness lifetime, or that don't match `req` are not returned.
+fn stored_response(
+    policy: &CachePolicy,
+    req: &http::request::Parts,
+    now: SystemTime,
+) -> Option<http::response::Parts> {
+    let mut stale_req = http::Request::builder()
+        .method(req.method.clone())
+        .uri(req.uri.clone())
+        .body(())
+        .unwrap()
+        .into_parts()
+        .0;
+    stale_req.headers = req.headers.clone();
+    stale_req.headers.remove(header::PRAGMA);
+    stale_req
+        .headers
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("max-stale"));
+    match policy.before_request(&stale_req, SystemTime::UNIX_EPOCH) {
+        BeforeRequest::Fresh(mut pts) => {
+            pts.headers
+                .insert(header::AGE, HeaderValue::from(policy.age(now).as_secs()));
+            pts.headers.insert(
+                header::DATE,
+                HeaderValue::from_str(&httpdate::fmt_http_date(now)).unwrap(),
+            );
+            if policy.is_stale(now) {
+                pts.headers
+                    .append(header::WARNING, HeaderValue::from_static(WARN_STALE));
+            }
+            Some(pts)
+        }
+        BeforeRequest::Stale { .. } => None,
+    }
+}
+
+/// Stale response to use when revalidation with upstream failed.
+///
+/// It is allowed within the `stale-if-error` or `stale-while-revalidate`
+/// period of the stored response.
+pub(crate) fn on_error(
+    policy: &CachePolicy,
+    req: &http::request::Parts,
+    now: SystemTime,
+) -> Option<http::response::Parts> {
+    let mut pts = stored_response(policy, req, now)?;
+    let allowed = [
+        directive_secs(&pts.headers, "stale-if-error"),
+        directive_secs(&pts.headers, "stale-while-revalidate"),
+    ]
+    .into_iter()
+    .flatten()
+    .max()?;
+    let stale = staleness(policy, now);
+    if stale > allowed {
+        tracing::debug!(?stale, ?allowed, "stale response can't be used on error");
+        return None;
+    }
+    pts.headers.append(
+        header::WARNING,
+        HeaderValue::from_static(WARN_REVALIDATION_FAILED),
+    );
+    Some(pts)
+}
+
+/// Stale response to serve while the entry is revalidated in background.
+///
+/// It is allowed within the `stale-while-revalidate` period of the stored response.
+pub(crate) fn while_revalidate(
+    policy: &CachePolicy,
+    req: &http::request::Parts,
+    now: SystemTime,
+) -> Option<http::response::Parts> {
+    let pts = stored_response(policy, req, now)?;
+    let allowed = directive_secs(&pts.headers, "stale-while-revalidate")?;
+    let stale = staleness(policy, now);
+    if stale > allowed {
+        tracing::debug!(
+            ?stale,
+            ?allowed,
+            "stale response can't be used while revalidating"
+        );
+        return None;
+    }
+    Some(pts)
+}
+
+/// Stored response to use in offline mode, which is served for `req` of the
+/// entry regardless of its freshness and cache directives.
+///
+/// `CachePolicy` only gives stored response headers when it is usable, so a
+/// stale response is updated as if upstream confirmed it with `304 Not
+/// Modified` to the revalidation request. `None` if it can't be confirmed.
+pub(crate) fn offline(
+    policy: &CachePolicy,
+    req: &http::request::Parts,
+    now: SystemTime,
+) -> Option<http::response::Parts> {
+    let mut plain_req = http::Request::builder()
+        .method(req.method.clone())
+        .uri(req.uri.clone())
+        .body(())
+        .unwrap()
+        .into_parts()
+        .0;
+    plain_req.headers = req.headers.clone();
+    // only validators of the stored response are sent in revalidation request
+    for name in [header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE] {
+        plain_req.headers.remove(name);
+    }
+    let mut pts = match policy.before_request(&plain_req, now) {
+        BeforeRequest::Fresh(pts) => pts,
+        BeforeRequest::Stale { request, .. } => {
+            let mut not_modified = http::Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .body(())
+                .unwrap()
+                .into_parts()
+                .0;
+            for (from, to) in [
+                (header::IF_NONE_MATCH, header::ETAG),
+                (header::IF_MODIFIED_SINCE, header::LAST_MODIFIED),
+            ] {
+                if let Some(v) = request.headers.get(from) {
+                    not_modified.headers.insert(to, v.clone());
+                }
+            }
+            match policy.after_response(&request, &not_modified, now) {
+                AfterResponse::NotModified(_, pts) => pts,
+                AfterResponse::Modified(_, _) => {
+                    tracing::error!("stored response can't be confirmed for offline mode");
+                    return None;
+                }
+            }
+        }
+    };
+    pts.headers
+        .insert(header::AGE, HeaderValue::from(policy.age(now).as_secs()));
+    pts.headers.insert(
+        header::DATE,
+        HeaderValue::from_str(&httpdate::fmt_http_date(now)).unwrap(),
+    );
+    if policy.is_stale(now) {
+        pts.headers
+            .append(header::WARNING, HeaderValue::from_static(WARN_STALE));
+    }
+    pts.headers
+        .append(header::WARNING, HeaderValue::from_static(WARN_DISCONNECTED));
+    Some(pts)
+}
+
+#[cfg(test)]
+mod tests {
+    use http_cache_semantics::CacheOptions;
+
+    use super::*;
+
+    /// Time the response is received.
+    fn received() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    fn after(secs: u64) -> SystemTime {
+        received() + Duration::from_secs(secs)
+    }
+
+    fn request() -> http::request::Parts {
+        http::Request::get("https://example.com/lib.js")
+            .header(header::HOST, "example.com")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    /// Policy of response with `headers`, received at [`received`].
+    fn policy(headers: &[(&str, &str)]) -> CachePolicy {
+        let mut res = http::Response::builder();
+        for (name, value) in headers {
+            res = res.header(*name, *value);
+        }
+        let res = res.body(()).unwrap().into_parts().0;
+        CachePolicy::new_options(&request(), &res, received(), CacheOptions::default())
+    }
+
+    fn warnings(pts: &http::response::Parts) -> Vec<&str> {
+        pts.headers
+            .get_all(header::WARNING)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn staleness_includes_age() {
+        let policy = policy(&[("cache-control", "max-age=60"), ("age", "10")]);
+        assert_eq!(staleness(&policy, after(30)), Duration::ZERO);
+        assert_eq!(staleness(&policy, after(100)), Duration::from_secs(50));
+    }
+
+    #[test]
+    fn while_revalidate_within_period() {
+        let swr = policy(&[("cache-control", "max-age=60, stale-while-revalidate=30")]);
+        let pts = while_revalidate(&swr, &request(), after(80)).unwrap();
+        assert_eq!(pts.headers[header::AGE], "80");
+        assert_eq!(warnings(&pts), [WARN_STALE]);
+        assert!(while_revalidate(&swr, &request(), after(100)).is_none());
+
+        let sie = policy(&[("cache-control", "max-age=60, stale-if-error=100")]);
+        assert!(while_revalidate(&sie, &request(), after(61)).is_none());
+    }
+
+    #[test]
+    fn on_error_within_period() {
+        let sie = policy(&[("cache-control", "max-age=60, stale-if-error=100")]);
+        let pts = on_error(&sie, &request(), after(150)).unwrap();
+        assert_eq!(warnings(&pts), [WARN_STALE, WARN_REVALIDATION_FAILED]);
+        assert!(on_error(&sie, &request(), after(170)).is_none());
+
+        // the longer of both periods is allowed
+        let both = policy(&[(
+            "cache-control",
+            "max-age=60, stale-while-revalidate=200, stale-if-error=10",
+        )]);
+        assert!(on_error(&both, &request(), after(250)).is_some());
+
+        let must_revalidate = policy(&[(
+            "cache-control",
+            "max-age=0, must-revalidate, stale-if-error=100",
+        )]);
+        assert!(on_error(&must_revalidate, &request(), after(1)).is_none());
+    }
+
+    #[test]
+    fn offline_regardless_of_directives() {
+        let policy = policy(&[
+            ("cache-control", "no-cache"),
+            ("etag", "\"v1\""),
+            ("connection", "x-hop"),
+            ("x-hop", "1"),
+            ("x-custom", "1"),
+        ]);
+        let pts = offline(&policy, &request(), after(1000)).unwrap();
+        assert_eq!(pts.status, StatusCode::OK);
+        assert_eq!(pts.headers[header::ETAG], "\"v1\"");
+        assert_eq!(pts.headers["x-custom"], "1");
+        assert!(!pts.headers.contains_key("x-hop"));
+        assert_eq!(pts.headers[header::AGE], "1000");
+        assert_eq!(warnings(&pts), [WARN_STALE, WARN_DISCONNECTED]);
+
+        // validators of the client are not taken as the stored ones
+        let mut req = request();
+        req.headers
+            .insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"v0\""));
+        assert!(offline(&policy, &req, after(1000)).is_some());
+    }
+
+    #[test]
+    fn offline_fresh_or_last_modified() {
+        let fresh = policy(&[("cache-control", "max-age=60")]);
+        let pts = offline(&fresh, &request(), after(10)).unwrap();
+        assert_eq!(warnings(&pts), [WARN_DISCONNECTED]);
+        let pts = offline(&fresh, &request(), after(100)).unwrap();
+        assert_eq!(warnings(&pts), [WARN_STALE, WARN_DISCONNECTED]);
+
+        let modified = "Mon, 13 Nov 2023 00:00:00 GMT";
+        let policy = policy(&[
+            ("cache-control", "max-age=0, must-revalidate"),
+            ("last-modified", modified),
+        ]);
+        let pts = offline(&policy, &request(), after(100)).unwrap();
+        assert_eq!(pts.headers[header::LAST_MODIFIED], modified);
+    }
+}
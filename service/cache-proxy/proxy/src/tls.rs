@@ -0,0 +1,365 @@
+//! TLS configuration of upstream connections.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::{
+    self,
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+#[derive(Debug)]
+pub enum TlsError {
+    ReadPem(PathBuf, rustls::pki_types::pem::Error),
+    NoCertificate(PathBuf),
+    InvalidPin(String),
+    NoRoots,
+    Rustls(rustls::Error),
+    Verifier(rustls::client::VerifierBuilderError),
+}
+impl Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ReadPem(p, e) => write!(f, "failed to read {}: {e:?}", p.display()),
+            Self::NoCertificate(p) => write!(f, "no certificate found in {}", p.display()),
+            Self::InvalidPin(p) => write!(f, "invalid pin {p:?}, expect sha256//<base64>"),
+            Self::NoRoots => f.write_str("no trusted root certificate"),
+            Self::Rustls(e) => write!(f, "invalid tls config: {e}"),
+            Self::Verifier(e) => write!(f, "failed to create certificate verifier: {e}"),
+        }
+    }
+}
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Rustls(e) => Some(e),
+            Self::Verifier(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Split DER element at the start of `der`, returns its contents, the whole
+/// element and remaining bytes.
+fn der_element(der: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let (_tag, rest) = der.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+    let (len, rest) = if len < 0x80 {
+        (len as usize, rest)
+    } else {
+        let n = (len & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n].iter().fold(0, |l, b| l << 8 | *b as usize);
+        (len, &rest[n..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header = der.len() - rest.len();
+    Some((&rest[..len], &der[..header + len], &rest[len..]))
+}
+
+/// DER encoded `SubjectPublicKeyInfo` of X.509 certificate `cert`.
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let (cert, _, _) = der_element(cert)?;
+    let (mut tbs, _, _) = der_element(cert)?;
+    // optional version
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_element(tbs)?.2;
+    }
+    // serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.2;
+    }
+    Some(der_element(tbs)?.1)
+}
+
+/// Verifier checking SPKI hash of end-entity certificates of pinned server
+/// names, after the certificate chain is verified.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: HashMap<String, Vec<[u8; 32]>>,
+}
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        if let Some(pins) = self.pins.get(&server_name.to_str().to_ascii_lowercase()) {
+            let spki = subject_public_key_info(end_entity).ok_or(
+                rustls::Error::InvalidCertificate(CertificateError::BadEncoding),
+            )?;
+            let digest: [u8; 32] = Sha256::digest(spki).into();
+            if !pins.contains(&digest) {
+                tracing::error!(
+                    server = %server_name.to_str(),
+                    spki = base64::engine::general_purpose::STANDARD.encode(digest),
+                    "public key of upstream is not pinned"
+                );
+                return Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ));
+            }
+        }
+        Ok(verified)
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| TlsError::ReadPem(path.to_path_buf(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+/// Trust roots, pinned keys and client certificate of upstream connections.
+#[derive(Debug)]
+pub struct TlsConfig {
+    native_roots: bool,
+    roots: Vec<CertificateDer<'static>>,
+    pins: HashMap<String, Vec<[u8; 32]>>,
+    client_auth: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+}
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl TlsConfig {
+    /// Config trusting native roots only.
+    pub fn new() -> Self {
+        Self {
+            native_roots: true,
+            roots: Vec::new(),
+            pins: HashMap::new(),
+            client_auth: None,
+        }
+    }
+    /// Whether roots of the platform are trusted.
+    pub fn native_roots(mut self, enabled: bool) -> Self {
+        self.native_roots = enabled;
+        self
+    }
+    /// Trust root certificates in PEM bundle `path`.
+    pub fn add_roots(mut self, path: &Path) -> Result<Self, TlsError> {
+        self.roots.extend(read_certs(path)?);
+        Ok(self)
+    }
+    /// Only accept certificates of `server_name` with public key matching one
+    /// of its pins, as `sha256//` followed by base64 SHA-256 hash of DER encoded
+    /// `SubjectPublicKeyInfo`.
+    pub fn pin(mut self, server_name: &str, pin: &str) -> Result<Self, TlsError> {
+        let digest = pin
+            .strip_prefix("sha256//")
+            .and_then(|h| base64::engine::general_purpose::STANDARD.decode(h).ok())
+            .and_then(|h| <[u8; 32]>::try_from(h).ok())
+            .ok_or_else(|| TlsError::InvalidPin(pin.to_string()))?;
+        self.pins
+            .entry(server_name.to_ascii_lowercase())
+            .or_default()
+            .push(digest);
+        Ok(self)
+    }
+    /// Present certificate chain in PEM file `cert` with private key in `key`
+    /// to upstream requesting client authentication.
+    pub fn client_cert(mut self, cert: &Path, key: &Path) -> Result<Self, TlsError> {
+        let key = PrivateKeyDer::from_pem_file(key)
+            .map_err(|e| TlsError::ReadPem(key.to_path_buf(), e))?;
+        self.client_auth = Some((read_certs(cert)?, key));
+        Ok(self)
+    }
+    pub fn client_config(self) -> Result<ClientConfig, TlsError> {
+        let mut roots = RootCertStore::empty();
+        if self.native_roots {
+            let native = rustls_native_certs::load_native_certs();
+            if !native.errors.is_empty() {
+                tracing::warn!(errors = ?native.errors, "failed to load some native roots");
+            }
+            let (added, ignored) = roots.add_parsable_certificates(native.certs);
+            tracing::debug!(added, ignored, "native roots loaded");
+        }
+        for cert in self.roots {
+            roots.add(cert).map_err(TlsError::Rustls)?;
+        }
+        if roots.is_empty() {
+            return Err(TlsError::NoRoots);
+        }
+        let roots = Arc::new(roots);
+        let builder = ClientConfig::builder();
+        let builder = if self.pins.is_empty() {
+            builder.with_root_certificates(roots)
+        } else {
+            let inner = WebPkiServerVerifier::builder(roots)
+                .build()
+                .map_err(TlsError::Verifier)?;
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    inner,
+                    pins: self.pins,
+                }))
+        };
+        match self.client_auth {
+            Some((certs, key)) => builder
+                .with_client_auth_cert(certs, key)
+                .map_err(TlsError::Rustls),
+            None => Ok(builder.with_no_client_auth()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Self-signed certificate of `cdn.example.com`, valid from 2026 to 2126.
+    const CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBujCCAWCgAwIBAgIUGZz8C1D8GfWB5/6iaCmGYZbkvZkwCgYIKoZIzj0EAwIw
+GjEYMBYGA1UEAwwPY2RuLmV4YW1wbGUuY29tMCAXDTI2MTAxNjE4NTIzOVoYDzIx
+MjYwOTIyMTg1MjM5WjAaMRgwFgYDVQQDDA9jZG4uZXhhbXBsZS5jb20wWTATBgcq
+hkjOPQIBBggqhkjOPQMBBwNCAAQM4VTJpMnhmGrGWU27FHYJhE80jDK0lSuzRbg9
+PGMQQb7BiZw0eL7n7R5eWlxNi8HE+p5eSFnRKCvWd7AnSr3Ro4GBMH8wHQYDVR0O
+BBYEFCwV3rMXu3C/LCB2tEQT5cdB31kSMB8GA1UdIwQYMBaAFCwV3rMXu3C/LCB2
+tEQT5cdB31kSMBoGA1UdEQQTMBGCD2Nkbi5leGFtcGxlLmNvbTAMBgNVHRMBAf8E
+AjAAMBMGA1UdJQQMMAoGCCsGAQUFBwMBMAoGCCqGSM49BAMCA0gAMEUCIGAm1rr7
+Y+fRayX5HyEFp0NZK++0Tjg4YWnZ672JLotsAiEAqEQ+A8hKPnfhD4//unbPK4vn
+lmVTU/Lbpt89lFkNKLw=
+-----END CERTIFICATE-----
+";
+    /// Pin of [`CERT`], computed by `openssl pkey -pubin -outform der`.
+    const PIN: &str = "sha256//mIE0laxnhZRKPcMT7JWe9V3dKfLZcrog2AvRmi67eks=";
+    const OTHER_PIN: &str = "sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    fn cert() -> CertificateDer<'static> {
+        CertificateDer::from_pem_slice(CERT.as_bytes()).unwrap()
+    }
+
+    /// Verifier trusting [`CERT`], with `pin` for its server name.
+    fn verifier(pin: &str) -> PinnedVerifier {
+        let config = TlsConfig::new().pin("CDN.example.com", pin).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert()).unwrap();
+        PinnedVerifier {
+            inner: WebPkiServerVerifier::builder(Arc::new(roots))
+                .build()
+                .unwrap(),
+            pins: config.pins,
+        }
+    }
+
+    fn verify(verifier: &PinnedVerifier, cert: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+        // 2027-01-01
+        let now = UnixTime::since_unix_epoch(Duration::from_secs(1_798_761_600));
+        let name = ServerName::try_from("cdn.example.com").unwrap();
+        verifier
+            .verify_server_cert(cert, &[], &name, &[], now)
+            .map(|_| ())
+    }
+
+    #[test]
+    fn spki_hash() {
+        let cert = cert();
+        let digest = Sha256::digest(subject_public_key_info(&cert).unwrap());
+        let pin = base64::engine::general_purpose::STANDARD.encode(digest);
+        assert_eq!(format!("sha256//{pin}"), PIN);
+    }
+
+    #[test]
+    fn pin_match() {
+        verify(&verifier(PIN), &cert()).unwrap();
+    }
+
+    #[test]
+    fn pin_mismatch() {
+        assert_eq!(
+            verify(&verifier(OTHER_PIN), &cert()),
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure
+            ))
+        );
+    }
+
+    #[test]
+    fn invalid_pin() {
+        for pin in [
+            "mIE0laxnhZRKPcMT7JWe9V3dKfLZcrog2AvRmi67eks=",
+            "sha1//mIE0laxnhZRKPcMT7JWe9V3dKfLZcrog2AvRmi67eks=",
+            "sha256//not base64",
+            // 31 bytes
+            "sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA==",
+        ] {
+            match TlsConfig::new().pin("cdn.example.com", pin) {
+                Err(TlsError::InvalidPin(p)) => assert_eq!(p, pin),
+                r => panic!("unexpected {r:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn no_roots() {
+        match TlsConfig::new().native_roots(false).client_config() {
+            Err(TlsError::NoRoots) => {}
+            r => panic!("unexpected {r:?}"),
+        }
+    }
+
+    #[test]
+    fn bad_encoding() {
+        let cert = cert();
+        let truncated = CertificateDer::from(&cert[..cert.len() - 1]);
+        assert_eq!(subject_public_key_info(&truncated), None);
+        // length in 5 bytes, more than any certificate needs
+        let mut long = vec![0x30, 0x85, 0, 0, 0, 0x01, 0xba];
+        long.extend_from_slice(&cert[4..]);
+        assert_eq!(subject_public_key_info(&long), None);
+    }
+}